@@ -0,0 +1,45 @@
+// Iteration over ropes built with different segment sizes. Smaller segments
+// make a deeper tree with shorter leaves, larger segments longer contiguous
+// reads. Run with `cargo bench`.
+
+extern crate strings;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use strings::rope::Rope;
+
+const TEXT_LEN: usize = 4 << 20;
+const ITERATIONS: u32 = 10;
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let line = "The quick brown fox jumps over the lazy d\u{f6}g. \u{2603}\n";
+    let text = line.repeat(TEXT_LEN / line.len());
+
+    for &segment in &[256, 0x10000] {
+        let mut rope = Rope::new_with_segment_size(segment);
+        rope.push_copy(&text);
+
+        let chars = time(|| {
+            black_box(rope.chars().count());
+        });
+        let segments = time(|| {
+            let bytes: usize = rope.segments_in(0..rope.len()).map(|(s, _)| s.len()).sum();
+            black_box(bytes);
+        });
+
+        println!("segment size {:>6}: {:>6} leaves, chars {:?}, segments {:?}",
+                 segment,
+                 rope.segment_count(),
+                 chars,
+                 segments);
+    }
+}
@@ -3,6 +3,12 @@
 // TODO use crates.io log instead
 #[macro_use]
 extern crate log;
+#[cfg(feature = "digest")]
+extern crate twox_hash;
+#[cfg(feature = "grapheme")]
+extern crate unicode_segmentation;
+#[cfg(feature = "width")]
+extern crate unicode_width;
 
 // TODO should probably expose data structures, not the modules
 pub mod string_buffer;
@@ -13,6 +19,16 @@ mod ropes;
 pub mod rope {
     pub use ::ropes::RopeSlice;
     pub use ::ropes::Rope;
+    pub use ::ropes::LineEnding;
+    pub use ::ropes::RopeError;
+    pub use ::ropes::StoragePolicy;
+    pub use ::ropes::RopeCharPos;
+    pub use ::ropes::Hunk;
+    pub use ::ropes::Edit;
+    pub use ::ropes::LineIndex;
+    pub use ::ropes::PatchError;
+    pub use ::ropes::RopeCharsRev;
+    pub use ::ropes::RopeLines;
 }
 
 pub mod src_rope {
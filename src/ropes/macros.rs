@@ -40,8 +40,8 @@ macro_rules! impl_rope {
                         self.root = Node::empty_inner();
                         self.len = 0;
                     }
-                    NodeAction::Adjust(adj) => self.len = (self.len as isize + adj) as usize,
-                    NodeAction::Change(node, adj) => {
+                    NodeAction::Adjust(adj, ..) => self.len = (self.len as isize + adj) as usize,
+                    NodeAction::Change(node, adj, ..) => {
                         self.root = *node;
                         self.len = (self.len as isize + adj) as usize;
                     }
@@ -62,6 +62,7 @@ macro_rules! impl_rope {
 
             pub fn replace_str(&mut self, start: usize, new_str: &str) {
                 assert!(start + new_str.len() <= self.len);
+                self.unshare();
                 self.root.replace(start, new_str);
             }
 
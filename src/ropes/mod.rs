@@ -6,6 +6,16 @@ mod src_rope;
 
 pub use self::rope::Rope;
 pub use self::rope::RopeSlice;
+pub use self::rope::LineEnding;
+pub use self::rope::RopeError;
+pub use self::rope::StoragePolicy;
+pub use self::rope::RopeCharPos;
+pub use self::rope::Hunk;
+pub use self::rope::Edit;
+pub use self::rope::LineIndex;
+pub use self::rope::PatchError;
+pub use self::rope::RopeCharsRev;
+pub use self::rope::RopeLines;
 
 pub use self::src_rope::Rope as SrcRope;
 pub use self::src_rope::RopeSlice as SrcRopeSlice;
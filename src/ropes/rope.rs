@@ -15,11 +15,20 @@
 // impl Default, Extend
 // impl DoubleEndedIter and ExactSizeIter for RopeChars
 // better allocation
-// balancing?
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::borrow::Cow;
+use std::error::Error;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
 use std::ops::Range;
-use util::utf8_char_width;
+use std::str::Utf8Error;
+use util::{char_count, decode_char, is_char_start, utf8_char_width};
+
+// The default maximum length of a leaf created by a single insertion.
+const DEFAULT_SEGMENT_SIZE: usize = 0x10000;
 
 // A Rope, based on an unbalanced binary tree. The rope is somewhat special in
 // that it tracks positions in the source text. So when locating a position in
@@ -29,10 +38,33 @@ use util::utf8_char_width;
 pub struct Rope {
     root: Node,
     len: usize,
-    // FIXME: Allocation is very dumb at the moment, we always add another
-    // buffer for every inserted string and we never resuse or collect old
-    // memory
-    storage: Vec<Vec<u8>>
+    // FIXME: Allocation is very dumb at the moment, by default we add another
+    // buffer for every inserted string (see `StoragePolicy` for alternatives)
+    // and we never resuse old memory. Old
+    // memory is only collected by `shrink_to_fit` (possibly triggered by
+    // `auto_compact`).
+    storage: Vec<Vec<u8>>,
+    // Inserted text longer than this is split into multiple leaves.
+    segment_size: usize,
+    // If set, compact storage after a removal if the ratio of live bytes to
+    // allocated bytes drops below this.
+    auto_compact: Option<f32>,
+    // The start of an incomplete char passed to `append_bytes`.
+    pending: Vec<u8>,
+    // How inserted text is allocated.
+    policy: StoragePolicy,
+    // For `StoragePolicy::Arena`, the index in `storage` of the buffer which
+    // new text is copied into.
+    arena: Option<usize>,
+    // For `StoragePolicy::Interned`, the indices in `storage` of buffers,
+    // keyed by a hash of their text.
+    interned: HashMap<u64, Vec<usize>>,
+    // True if more than one insertion refers to the same interned buffer.
+    shared: bool,
+    // For `prepend`, the index in `storage` of a buffer which is filled from
+    // the back. If the leftmost leaf is the left child of the root and lies
+    // in this buffer, text is prepended by growing that leaf backwards.
+    front: Option<usize>,
 }
 
 // A view over a portion of a Rope. Analagous to string slices (`str`);
@@ -45,6 +77,88 @@ pub struct RopeSlice<'rope> {
     len: usize,
 }
 
+// A kind of line terminator.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LineEnding {
+    // `\n`
+    Lf,
+    // `\r\n`
+    CrLf,
+    // `\r`
+    Cr,
+}
+
+// How a rope allocates storage for inserted text.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StoragePolicy {
+    // Allocate a new buffer for every insertion (except while a buffer kept
+    // by `Rope::clear_and_keep_capacity` or allocated by
+    // `Rope::with_capacity_lines` has room).
+    PerInsert,
+    // Copy inserted text into shared buffers of (at least) the given size.
+    Arena(usize),
+    // Reuse the buffer of an earlier insertion of identical text. In-place
+    // edits of a rope which shares buffers first copy its text into private
+    // storage.
+    Interned,
+}
+
+// An error from a checked operation on a rope.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RopeError {
+    // The offset is past the end of the rope.
+    OutOfBounds { offset: usize, len: usize },
+    // The offset is inside a char.
+    NotCharBoundary(usize),
+    // There is no such line, or the column (in chars) is past the end of the
+    // line.
+    InvalidPosition { line: usize, col: usize },
+    // The two ranges overlap.
+    Overlap(Range<usize>, Range<usize>),
+    // The range starts after it ends.
+    ReversedRange(Range<usize>),
+}
+
+// A single change in a patch: replace `removed_len` bytes at `start_byte`
+// with `insert_text`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hunk {
+    pub start_byte: usize,
+    pub removed_len: usize,
+    pub insert_text: String,
+}
+
+// The shape of an edit to a rope: `removed_len` bytes at `start_byte` were
+// replaced by `inserted_len` bytes. See `Rope::adjust_offset`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Edit {
+    pub start_byte: usize,
+    pub removed_len: usize,
+    pub inserted_len: usize,
+}
+
+// The offsets of the starts of the lines of a rope, for converting between
+// lines and byte offsets by binary search (see `Rope::build_line_index`).
+// The index is a snapshot: it is not updated when the rope is edited, so
+// after an edit the caller must rebuild it before using it again.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LineIndex {
+    // The first is always 0.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+// An error from `Rope::apply_patch`. Hunks are identified by their index in
+// the patch.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PatchError {
+    // The hunk extends past the end of the rope, or does not start and end on
+    // char boundaries.
+    OutOfBounds(usize),
+    // The two hunks overlap, or start at the same offset.
+    Overlap(usize, usize),
+}
+
 // An iterator over the chars in a rope.
 pub struct RopeChars<'rope> {
     data: RopeSlice<'rope>,
@@ -53,29 +167,169 @@ pub struct RopeChars<'rope> {
     abs_byte: usize,
 }
 
+// A saved position of a `RopeChars` iterator, see `RopeChars::position`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RopeCharPos {
+    node: usize,
+    byte: usize,
+    abs_byte: usize,
+}
+
+// An iterator over the chars in a rope, from the end to the start.
+pub struct RopeCharsRev<'rope> {
+    data: RopeSlice<'rope>,
+    // One past the index of the node we're currently reading.
+    cur_node: usize,
+    // One past the index of the next byte to read in the current node's chunk.
+    cur_byte: usize,
+    // The offset in the rope of the end of the unread text.
+    abs_byte: usize,
+}
+
+// An iterator over the lines in a rope, not including line terminators.
+pub struct RopeLines<'rope> {
+    rope: &'rope Rope,
+    // The start of the next line, or None if there are no more lines.
+    next: Option<usize>,
+}
+
 
 impl_rope!(Rope);
 
 impl Rope {
     // Create an empty rope.
     pub fn new() -> Rope {
+        Rope::new_with_segment_size(DEFAULT_SEGMENT_SIZE)
+    }
+
+    // Create an empty rope which splits inserted text into leaves of at most
+    // `segment` bytes (or a single char, if that is longer). Smaller segments
+    // give cheaper edits within a leaf, larger segments give shallower trees
+    // and longer contiguous reads.
+    pub fn new_with_segment_size(segment: usize) -> Rope {
+        assert!(segment > 0, "segment size must be non-zero");
         Rope {
             root: Node::empty_inner(),
             len: 0,
             storage: vec![],
+            segment_size: segment,
+            auto_compact: None,
+            pending: vec![],
+            policy: StoragePolicy::PerInsert,
+            arena: None,
+            interned: HashMap::new(),
+            shared: false,
+            front: None,
+        }
+    }
+
+    // Create an empty rope which allocates storage according to `policy`.
+    pub fn with_policy(policy: StoragePolicy) -> Rope {
+        if let StoragePolicy::Arena(size) = policy {
+            assert!(size > 0, "arena size must be non-zero");
         }
+        let mut result = Rope::new();
+        result.policy = policy;
+        result
+    }
+
+    // Create an empty rope with room for about `lines` lines of
+    // `avg_line_len` bytes each (plus newlines). Inserted text is copied into
+    // a single buffer of that size, allocated up front, so loading text of the
+    // expected size never allocates storage again. The hint only applies to
+    // that first buffer; the rope otherwise uses `StoragePolicy::PerInsert`.
+    pub fn with_capacity_lines(lines: usize, avg_line_len: usize) -> Rope {
+        let size = ::std::cmp::max(lines.saturating_mul(avg_line_len.saturating_add(1)), 1);
+        let mut result = Rope::new();
+        result.storage.push(Vec::with_capacity(size));
+        result.arena = Some(0);
+        result
     }
 
     // Uses text as initial storage.
     pub fn from_string(text: String) -> Rope {
-        // TODO should split very large texts into segments as we insert
-
         let mut result = Rope::new();
         result.insert(0, text);
         result
     }
 
+    // Create a rope from `text`, dropping a leading byte order mark
+    // (`\u{feff}`) if there is one, as is usual when loading a file. Offsets
+    // in the rope count from the first char after the BOM.
+    pub fn from_str_stripping_bom(text: &str) -> Rope {
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+        Rope::from_string(text.to_string())
+    }
+
+    // Create a rope from the UTF-8 text read from `r`. The text is read in
+    // segments of about `segment` bytes, each of which becomes a leaf of a
+    // balanced tree. This is the recommended way to load large files.
+    pub fn load<R: Read>(mut r: R, segment: usize) -> io::Result<Rope> {
+        let mut result = Rope::new_with_segment_size(segment);
+        let mut leaves = vec![];
+        // Bytes of an incomplete char at the end of the previous segment.
+        let mut carry = vec![];
+        loop {
+            let mut buf = Vec::with_capacity(segment + carry.len());
+            buf.append(&mut carry);
+            let read = r.by_ref().take(segment as u64).read_to_end(&mut buf)?;
+            if read == 0 {
+                if !buf.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "stream ends with an incomplete char"));
+                }
+                break;
+            }
+
+            let valid = match ::std::str::from_utf8(&buf) {
+                Ok(_) => buf.len(),
+                Err(ref e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            carry = buf.split_off(valid);
+
+            if !buf.is_empty() {
+                leaves.push(Lnode::new(buf.as_ptr(), buf.len()));
+                result.len += buf.len();
+                result.storage.push(buf);
+            }
+        }
+
+        if !leaves.is_empty() {
+            result.root = Node::from_leaves(&leaves);
+        }
+        Ok(result)
+    }
+
+    // Create a rope from `lines`, separated by newlines. The lines should not
+    // contain newlines themselves.
+    pub fn from_lines<I, S>(lines: I) -> Rope
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        Rope::join(lines, "\n")
+    }
+
+    // Create a rope from `parts`, separated by `sep`, like `slice::join`. The
+    // text is copied into a single buffer.
+    pub fn join<I, S>(parts: I, sep: &str) -> Rope
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        let mut text = String::new();
+        for (i, part) in parts.into_iter().enumerate() {
+            if i > 0 {
+                text.push_str(sep);
+            }
+            text.push_str(part.as_ref());
+        }
+        Rope::from_string(text)
+    }
+
     pub fn insert(&mut self, start: usize, text: String) {
+        assert!(start <= self.len, "byte {} out of bounds of rope (len {})", start, self.len);
+        assert!(self.is_char_boundary(start),
+                "byte {} is not a char boundary of rope (len {})", start, self.len);
         self.insert_inner(start,
                           text,
                           |this, node| this.root.insert(node, start))
@@ -94,16 +348,14 @@ impl Rope {
         debug_assert!(start <= self.len, "insertion out of bounds of rope");
 
         let len = text.len();
-        let storage = text.into_bytes();
-        let new_node = Box::new(Node::new_leaf(&storage[..][0] as *const u8, len));
-        self.storage.push(storage);
+        let new_node = Box::new(self.alloc(text));
 
         match do_insert(self, new_node) {
-            NodeAction::Change(n, adj) => {
+            NodeAction::Change(n, adj, _) => {
                 assert!(adj as usize == len);
                 self.root = *n;
             }
-            NodeAction::Adjust(adj) => {
+            NodeAction::Adjust(adj, _) => {
                 assert!(adj as usize == len);
             }
             _ => panic!("Unexpected action")
@@ -111,754 +363,5979 @@ impl Rope {
         self.len += len;
     }
 
-    pub fn remove(&mut self, start: usize, end: usize) {
-        self.remove_inner(start, end, |this| this.root.remove(start, end))
-    }
-}
+    // Store `text` according to the rope's storage policy and make a node for
+    // it.
+    fn alloc(&mut self, text: String) -> Node {
+        match self.policy {
+            StoragePolicy::PerInsert => {
+                // Use the buffer kept by `clear_and_keep_capacity` or
+                // allocated by `with_capacity_lines`, if any, while it has
+                // room.
+                if let Some(node) = self.copy_to_arena(&text) {
+                    return node;
+                }
+                let storage = text.into_bytes();
+                let node = self.segment(&storage);
+                self.storage.push(storage);
+                node
+            }
+            StoragePolicy::Arena(size) => {
+                if let Some(node) = self.copy_to_arena(&text) {
+                    return node;
+                }
+                let size = ::std::cmp::max(size, text.len());
+                self.storage.push(Vec::with_capacity(size));
+                self.arena = Some(self.storage.len() - 1);
+                self.copy_to_arena(&text).unwrap()
+            }
+            StoragePolicy::Interned => {
+                let mut hasher = DefaultHasher::new();
+                text.hash(&mut hasher);
+                let key = hasher.finish();
 
-impl<'rope> RopeSlice<'rope> {
-    fn empty<'r>() -> RopeSlice<'r> {
-        RopeSlice {
-            nodes: vec![],
-            start: 0,
-            len: 0,
+                let found = self.interned.get(&key).and_then(|indices| {
+                    indices.iter().cloned().find(|&i| self.storage[i] == text.as_bytes())
+                });
+                let i = match found {
+                    Some(i) => {
+                        self.shared = true;
+                        i
+                    }
+                    None => {
+                        self.storage.push(text.into_bytes());
+                        let i = self.storage.len() - 1;
+                        self.interned.entry(key).or_default().push(i);
+                        i
+                    }
+                };
+                self.segment(&self.storage[i])
+            }
         }
     }
 
-    pub fn iter_chars(self) -> RopeChars<'rope> {
-        let start = self.start;
-        RopeChars {
-            data: self,
-            cur_node: 0,
-            cur_byte: start,
-            abs_byte: start,
+    // Copy `text` into the arena buffer and make a node for it, or return
+    // `None` if there is no arena buffer or it does not have room for `text`.
+    fn copy_to_arena(&mut self, text: &str) -> Option<Node> {
+        let i = self.arena?;
+        if self.storage[i].capacity() - self.storage[i].len() < text.len() {
+            return None;
         }
+
+        // There is room for `text`, so this never reallocates and existing
+        // leaves in the arena stay valid.
+        let start = self.storage[i].len();
+        self.storage[i].extend_from_slice(text.as_bytes());
+        Some(self.segment(&self.storage[i][start..]))
     }
-}
 
-impl<'rope> Iterator for RopeChars<'rope> {
-    type Item = (char, usize);
-    fn next(&mut self) -> Option<(char, usize)> {
-        if self.cur_node >= self.data.nodes.len() {
-            return None;
+    // A copy of the text of the rope.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut text = Vec::with_capacity(self.len);
+        for chunk in self.full_slice().chunks() {
+            text.extend_from_slice(chunk);
         }
+        text
+    }
 
-        let byte = self.abs_byte;
-        let node = self.data.nodes[self.cur_node];
+    // Make the rope empty and free all its storage.
+    fn clear_storage(&mut self) {
+        self.root = Node::empty_inner();
+        self.len = 0;
+        self.storage = vec![];
+        self.arena = None;
+        self.interned.clear();
+        self.shared = false;
+        self.front = None;
+    }
 
-        if self.cur_node == self.data.nodes.len() - 1 {
+    // Make the rope empty and free all its storage.
+    pub fn clear(&mut self) {
+        self.clear_storage();
+        self.pending.clear();
+    }
 
-            if self.cur_node == 0 && self.cur_byte > self.data.len + self.data.start {
-                // this is the first & last node
-                // we are at the end of the target slice
-                return None
-            }
+    // Take the buffers which hold the rope's text, leaving the rope empty
+    // (since every leaf of a rope must point into a buffer the rope owns).
+    // The buffers hold exactly the text of the rope, in order. If the rope
+    // has been edited so that its storage holds other bytes (removed text,
+    // buffers out of order, or unused space from `prepend`), it is compacted
+    // first, as by `shrink_to_fit`; otherwise no text is copied. Together with
+    // `adopt_storage`, this moves text between ropes, or into a pool of
+    // buffers.
+    pub fn take_storage(&mut self) -> Vec<Vec<u8>> {
+        if !self.storage_is_text() {
+            self.shrink_to_fit();
+        }
+        let result = ::std::mem::take(&mut self.storage);
+        self.clear_storage();
+        result
+    }
 
-            if self.cur_byte + 1 > node.len - self.data.start {
-                // this is the last node
-                // we are at the end of the target slice
-                return None
+    // Whether the storage buffers, concatenated in order, are exactly the
+    // text of the rope.
+    fn storage_is_text(&self) -> bool {
+        let slice = self.full_slice();
+        let mut leaves = slice.nodes.iter().filter(|l| l.len > 0);
+        for buf in &self.storage {
+            let mut at = buf.as_ptr() as usize;
+            let end = at + buf.len();
+            while at < end {
+                match leaves.next() {
+                    Some(leaf) if leaf.text as usize == at => at += leaf.len,
+                    _ => return false,
+                }
+            }
+            if at != end {
+                return false;
             }
         }
+        leaves.next().is_none()
+    }
 
-        if self.cur_byte >= node.len {
-            self.cur_byte = 0;
-            self.cur_node += 1;
-            return self.next();
+    // Take ownership of `storage` and append the text of each buffer to the
+    // rope, in order. The new leaves point into the adopted buffers, so no
+    // text is copied. Each buffer must be valid UTF-8; if one is not, the
+    // rope is unchanged and the buffers are dropped.
+    pub fn adopt_storage(&mut self, storage: Vec<Vec<u8>>) -> Result<(), Utf8Error> {
+        for buf in &storage {
+            ::std::str::from_utf8(buf)?;
         }
 
-        let result = self.read_char();
-        return Some((result, byte));
+        for buf in storage {
+            if buf.is_empty() {
+                continue;
+            }
+            let len = buf.len();
+            let node = Box::new(self.segment(&buf));
+            // Moving `buf` does not move its contents, so `node` stays valid.
+            self.storage.push(buf);
+            match self.root.insert(node, self.len) {
+                NodeAction::Change(n, ..) => self.root = *n,
+                NodeAction::Adjust(..) => {}
+                _ => panic!("Unexpected action"),
+            }
+            self.len += len;
+        }
+        Ok(())
     }
-}
 
-impl<'rope> RopeChars<'rope> {
-    fn read_char(&mut self) -> char {
-        let first_byte = self.read_byte();
-        let width = utf8_char_width(first_byte);
-        if width == 1 {
-            return first_byte as char
+    // Make the rope empty, but keep its largest storage buffer. Later inserts
+    // are copied into that buffer while it has room (unless the storage
+    // policy is `Interned`), which avoids reallocating when a rope is
+    // repeatedly cleared and refilled.
+    pub fn clear_and_keep_capacity(&mut self) {
+        let largest = (0..self.storage.len()).max_by_key(|&i| self.storage[i].capacity());
+        let buf = largest.map(|i| self.storage.swap_remove(i));
+        self.clear();
+        if let Some(mut buf) = buf {
+            buf.clear();
+            self.storage.push(buf);
+            self.arena = Some(0);
         }
-        if width == 0 {
-            panic!("non-utf8 char in rope");
+    }
+
+    // Copy the rope's text into private storage if it shares interned
+    // buffers, so that it can be edited in place.
+    fn unshare(&mut self) {
+        if self.shared {
+            self.shrink_to_fit();
         }
-        let mut buf = [first_byte, 0, 0, 0];
-        {
-            let mut start = 1;
-            while start < width {
-                buf[start] = self.read_byte();
-                start += 1;
+    }
+
+    // Make a node for `text`, split into leaves of at most `segment_size`
+    // bytes (except where a single char is longer than that).
+    fn segment(&self, text: &[u8]) -> Node {
+        let mut leaves = vec![];
+        let mut start = 0;
+        while start < text.len() {
+            let mut end = ::std::cmp::min(start + self.segment_size, text.len());
+            while end > start && end < text.len() && !is_char_start(text[end]) {
+                end -= 1;
             }
+            if end == start {
+                end += utf8_char_width(text[start]);
+            }
+            leaves.push(Lnode::new(&text[start] as *const u8, end - start));
+            start = end;
         }
-        match ::std::str::from_utf8(&buf[..width]).ok() {
-            Some(s) => s.chars().nth(0).expect("FATAL: we checked presence of this before"),
-            None => panic!("bad chars in rope")
-        }
+        Node::from_leaves(&leaves)
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let node = self.data.nodes[self.cur_node];
-        let addr = node.text as usize + self.cur_byte;
-        self.cur_byte += 1;
-        self.abs_byte += 1;
-        let addr = addr as *const u8;
-        unsafe {
-            *addr
+    pub fn remove(&mut self, start: usize, end: usize) {
+        assert!(start <= end && end <= self.len,
+                "range {:?} out of bounds of rope (len {})", start..end, self.len);
+        self.remove_inner(start, end, |this| this.root.remove(start, end));
+
+        if let Some(threshold) = self.auto_compact {
+            if (self.len as f32) < threshold * self.capacity() as f32 {
+                self.shrink_to_fit();
+            }
         }
     }
-}
 
-impl ::std::str::FromStr for Rope {
-    type Err = ();
-    fn from_str(text: &str) -> Result<Rope, ()> {
-        // TODO should split large texts into segments as we insert
+    // The number of bytes allocated for the rope's text, including text which
+    // has been removed but not yet collected.
+    pub fn capacity(&self) -> usize {
+        self.storage.iter().map(|s| s.capacity()).sum()
+    }
 
-        let mut result = Rope::new();
-        result.insert_copy(0, text);
-        Ok(result)
+    // Copy the text of the rope into new storage, freeing memory used by
+    // removed text.
+    pub fn shrink_to_fit(&mut self) {
+        let text = self.to_bytes();
+        let text = String::from_utf8(text).expect("non-utf8 char in rope");
+        self.set_text(text);
     }
-}
 
-impl<'a> fmt::Display for RopeSlice<'a> {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        if self.nodes.len() == 0 {
-            return Ok(());
+    // Replace the contents of the rope with `text`, in fresh storage and a
+    // balanced tree. Edits which touch many places in the rope build their
+    // result in one pass and install it with this, rather than splicing each
+    // change (which deepens the tree with every edit).
+    fn set_text(&mut self, text: String) {
+        self.clear_storage();
+        self.insert(0, text);
+    }
+
+    // If the rope is no longer than its segment size, copy its text into a
+    // single leaf. This makes reads of a small rope contiguous.
+    pub fn flatten(&mut self) {
+        if self.len == 0 || self.len > self.segment_size {
+            return;
         }
 
-        let last_idx = self.nodes.len() - 1;
-        for (i, n) in self.nodes.iter().enumerate() {
-            let mut ptr = n.text;
-            let mut len = n.len;
-            if i == 0 {
-                ptr = (ptr as usize + self.start) as *const u8;
-                len -= self.start;
-            }
-            if i == last_idx {
-                len = self.len;
-            }
-            unsafe {
-                try!(write!(fmt,
-                            "{}",
-                            ::std::str::from_utf8(::std::slice::from_raw_parts(ptr, len)).unwrap()));
-            }
+        let text = self.to_bytes();
+        let len = text.len();
+        self.clear_storage();
+        self.root = Node::new_leaf(text.as_ptr(), len);
+        self.storage.push(text);
+        self.len = len;
+    }
+
+    // Automatically call `shrink_to_fit` after a removal when less than
+    // `threshold` (between 0 and 1) of the allocated storage is in use.
+    pub fn set_auto_compact(&mut self, threshold: f32) {
+        assert!((0.0..=1.0).contains(&threshold), "bad compaction threshold {}", threshold);
+        self.auto_compact = Some(threshold);
+    }
+
+    // Panic if the text of any leaf does not lie entirely within one of the
+    // rope's storage buffers. Only available with the `debug-checks` feature
+    // (and in tests).
+    #[cfg(any(test, feature = "debug-checks"))]
+    pub fn assert_pointers_valid(&self) {
+        for leaf in self.full_slice().nodes {
+            let start = leaf.text as usize;
+            let end = start + leaf.len;
+            let valid = self.storage.iter().any(|buf| {
+                let buf_start = buf.as_ptr() as usize;
+                buf_start <= start && end <= buf_start + buf.len()
+            });
+            assert!(valid, "leaf at {:#x} (len {}) is outside the rope's storage", start, leaf.len);
         }
-        Ok(())
     }
-}
 
-impl<'a> fmt::Debug for RopeSlice<'a> {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let last_idx = self.nodes.len() - 1;
-        for (i, n) in self.nodes.iter().enumerate() {
-            let mut ptr = n.text;
-            let mut len = n.len;
-            if i == 0 {
-                ptr = (ptr as usize + self.start) as *const u8;
-                len -= self.start;
-            } else {
-                try!(write!(fmt, "|"));
-            }
-            if i == last_idx {
-                len = self.len;
-            }
-            unsafe {
-                try!(write!(fmt,
-                            "\"{}\"",
-                            ::std::str::from_utf8(::std::slice::from_raw_parts(ptr, len)).unwrap()));
+    // Append UTF-8 encoded text to the rope. If `bytes` ends part way through
+    // a char, the start of the char is kept and completed by the next call.
+    // Returns an error and leaves the rope unchanged if `bytes` is not valid
+    // UTF-8.
+    pub fn append_bytes(&mut self, bytes: &[u8]) -> Result<(), Utf8Error> {
+        let mut buf = ::std::mem::take(&mut self.pending);
+        let pending_len = buf.len();
+        buf.extend_from_slice(bytes);
+
+        let text = match String::from_utf8(buf) {
+            Ok(text) => text,
+            Err(e) => {
+                let err = e.utf8_error();
+                let mut buf = e.into_bytes();
+                if err.error_len().is_some() {
+                    buf.truncate(pending_len);
+                    self.pending = buf;
+                    return Err(err);
+                }
+                self.pending = buf.split_off(err.valid_up_to());
+                String::from_utf8(buf).expect("checked by from_utf8")
             }
-        }
+        };
+        self.push(text);
         Ok(())
     }
-}
 
-impl fmt::Display for Rope {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(fmt, "{}", self.root)
+    // Finish a sequence of `append_bytes` calls. Returns an error if the last
+    // call ended with an incomplete char, which is discarded.
+    pub fn finish_append(&mut self) -> Result<(), Utf8Error> {
+        let pending = ::std::mem::take(&mut self.pending);
+        ::std::str::from_utf8(&pending).map(|_| ())
     }
-}
 
-impl fmt::Debug for Rope {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(fmt, "{:?}", self.root)
+    // Shorten the rope to `new_len` bytes. Has no effect if `new_len` is
+    // greater than the current length.
+    pub fn truncate(&mut self, new_len: usize) {
+        let len = self.len;
+        if new_len < len {
+            self.remove(new_len, len);
+        }
     }
-}
 
-impl fmt::Display for Node {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            Node::InnerNode(Inode { ref left, ref right, .. }) => {
-                if let Some(ref left) = *left {
-                    write!(fmt, "{}", left)
-                } else {
-                    Ok(())
-                }.and_then(|_| if let Some(ref right) = *right {
-                    write!(fmt, "{}", right)
-                } else {
-                    Ok(())
-                })
-            }
-            Node::LeafNode(Lnode{ ref text, len, .. }) => {
-                unsafe {
-                    write!(fmt,
-                           "{}",
-                           ::std::str::from_utf8(::std::slice::from_raw_parts(*text, len)).unwrap())
-                }
-            }
+    // Keep only the first `n` lines (see `lines`). The newline which ended the
+    // last kept line is removed too, so afterwards `len_lines()` is `n` (or 1
+    // if `n` is 0). Has no effect if the rope has no more than `n` lines.
+    pub fn truncate_lines(&mut self, n: usize) {
+        if n == 0 {
+            self.truncate(0);
+            return;
+        }
+        if let Some(range) = self.line_byte_range(n - 1) {
+            self.truncate(range.end);
         }
     }
-}
 
-impl fmt::Debug for Node {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            Node::InnerNode(Inode { ref left, ref right, weight, .. }) => {
-                try!(write!(fmt, "("));
-                if let Some(ref left) = *left {
-                    try!(write!(fmt, "left: {:?}", &**left));
-                } else {
-                    try!(write!(fmt, "left: ()"));
-                }
-                try!(write!(fmt, ", "));
-                if let Some(ref right) = *right {
-                    try!(write!(fmt, "right: {:?}", &**right));
+    // Shorten the rope to at most `max_bytes` bytes, without splitting a
+    // char.
+    pub fn truncate_to_boundary(&mut self, max_bytes: usize) {
+        if max_bytes < self.len {
+            let new_len = self.clamp_to_boundary(max_bytes);
+            self.truncate(new_len);
+        }
+    }
+
+    // As `insert_copy`, but returns an error rather than corrupting the rope
+    // if `at` is not a char boundary within the rope. The rope is unchanged
+    // if an error is returned.
+    pub fn insert_str_checked(&mut self, at: usize, text: &str) -> Result<(), RopeError> {
+        self.check_offset(at)?;
+        self.insert_copy(at, text);
+        Ok(())
+    }
+
+    // As `insert_copy`, but then rebalances the path to the inserted text if
+    // it has become too deep (more than about twice the log of the rope's
+    // length). The lowest subtree on the path which is much deeper than the
+    // log of its number of leaves is rebuilt, leaving the rest of the tree
+    // alone. Using this for many scattered inserts keeps the depth of the tree
+    // logarithmic.
+    pub fn insert_balanced(&mut self, at: usize, text: &str) {
+        self.insert_copy(at, text);
+        if text.is_empty() {
+            return;
+        }
+
+        // Roughly log2(n).
+        fn bits(n: usize) -> usize {
+            (usize::BITS - n.leading_zeros()) as usize
+        }
+
+        loop {
+            // The path to the leaf containing the start of `text`, and
+            // whether each step on it goes left.
+            let mut path = vec![&self.root];
+            let mut lefts = vec![];
+            let mut byte = at;
+            let mut node = &self.root;
+            while let Node::InnerNode(ref i) = *node {
+                if byte < i.weight {
+                    node = i.left.as_deref().unwrap();
+                    lefts.push(true);
                 } else {
-                    try!(write!(fmt, "right: ()"));
+                    byte -= i.weight;
+                    node = i.right.as_deref().unwrap();
+                    lefts.push(false);
                 }
-                write!(fmt, "; {})", weight)
+                path.push(node);
             }
-            Node::LeafNode(Lnode{ ref text, len, .. }) => {
-                unsafe {
-                    write!(fmt,
-                           "(\"{}\"; {})",
-                           ::std::str::from_utf8(::std::slice::from_raw_parts(*text, len)).unwrap(),
-                           len)
+            if lefts.len() <= 2 * bits(self.len) {
+                return;
+            }
+
+            // Walk back up the path, counting leaves, to find the subtree to
+            // rebuild. The root always qualifies, since a rope has no more
+            // leaves than bytes.
+            let mut leaf_count = 1;
+            let mut scapegoat = 0;
+            for k in (0..lefts.len()).rev() {
+                if let Node::InnerNode(ref i) = *path[k] {
+                    let sibling = if lefts[k] { &i.right } else { &i.left };
+                    leaf_count += sibling.as_ref().map_or(0, |n| n.leaves().len());
+                }
+                if lefts.len() - k > 2 * bits(leaf_count) {
+                    scapegoat = k;
+                    break;
                 }
             }
+
+            let leaves: Vec<Lnode> = path[scapegoat].leaves().into_iter().cloned().collect();
+            let mut node = &mut self.root;
+            for &left in &lefts[..scapegoat] {
+                let child = match *node {
+                    Node::InnerNode(ref mut i) => if left { &mut i.left } else { &mut i.right },
+                    Node::LeafNode(_) => unreachable!(),
+                };
+                node = child.as_deref_mut().unwrap();
+            }
+            *node = Node::from_leaves(&leaves);
         }
     }
-}
 
-#[derive(Clone, Eq, PartialEq)]
-enum Node {
-    InnerNode(Inode),
-    LeafNode(Lnode),
-}
+    // Insert `text` at column `col` (in chars) of `line`, returning the byte
+    // offset of the end of the inserted text. The column may be the length of
+    // the line. The rope is unchanged if an error is returned.
+    pub fn insert_at(&mut self, line: usize, col: usize, text: &str) -> Result<usize, RopeError> {
+        let at = {
+            let range = self.line_byte_range(line).ok_or(RopeError::InvalidPosition { line, col })?;
+            let slice = self.slice(range.clone());
+            let at = slice.char_indices()
+                          .map(|(i, _)| range.start + i)
+                          .chain(Some(range.end))
+                          .nth(col);
+            at.ok_or(RopeError::InvalidPosition { line, col })?
+        };
+        self.insert_copy(at, text);
+        Ok(at + text.len())
+    }
 
-#[derive(Clone, Eq, PartialEq)]
-struct Inode {
-    weight: usize,
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
-}
+    // Insert `text` at the start of the rope. Repeated prepends are amortised
+    // O(1) and only deepen the tree when a new buffer is allocated.
+    pub fn prepend(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if text.len() > self.segment_size {
+            self.insert_copy(0, text);
+            return;
+        }
 
-#[derive(Clone, Eq, PartialEq)]
-struct Lnode {
-    text: *const u8,
-    len: usize,
-}
+        // Try to grow the leftmost leaf backwards into its buffer.
+        let front = self.front.map(|i| (i, self.storage[i].as_ptr() as usize));
+        if let (Some((i, buf_start)), &mut Node::InnerNode(ref mut root)) = (front, &mut self.root) {
+            if let Some(Node::LeafNode(ref mut leaf)) = root.left.as_deref_mut() {
+                let start = leaf.text as usize;
+                let fits = start >= buf_start + text.len() &&
+                           start < buf_start + self.storage[i].len() &&
+                           leaf.len + text.len() <= self.segment_size;
+                if fits {
+                    let offset = start - buf_start - text.len();
+                    self.storage[i][offset..offset + text.len()].copy_from_slice(text.as_bytes());
+                    let chars = text.chars().count();
+                    leaf.text = self.storage[i][offset..].as_ptr();
+                    leaf.len += text.len();
+                    leaf.chars += chars;
+                    root.weight += text.len();
+                    root.char_weight += chars;
+                    self.len += text.len();
+                    return;
+                }
+            }
+        }
 
-impl Node {
-    fn empty_inner() -> Node {
-        Node::InnerNode(Inode {
-            left: None,
-            right: None,
-            weight: 0,
-        })
-    }
+        // Start a new buffer, at least twice the size of the last one, and
+        // put its leaf at the top of the tree.
+        let last_size = self.front.map_or(0, |i| self.storage[i].len());
+        let size = ::std::cmp::min(::std::cmp::max(::std::cmp::max(2 * last_size, 16), text.len()),
+                                   self.segment_size);
+        let mut buf = vec![0; size];
+        buf[size - text.len()..].copy_from_slice(text.as_bytes());
+        let leaf = Node::new_leaf(buf[size - text.len()..].as_ptr(), text.len());
+        self.storage.push(buf);
+        self.front = Some(self.storage.len() - 1);
 
-    fn new_inner(left: Option<Box<Node>>,
-                 right: Option<Box<Node>>,
-                 weight: usize)
-    -> Node {
-        Node::InnerNode(Inode {
-            left: left,
-            right: right,
-            weight: weight,
-        })
+        let old_root = ::std::mem::replace(&mut self.root, Node::empty_inner());
+        let right = if self.len > 0 { Some(Box::new(old_root)) } else { None };
+        self.root = Node::new_inner(Some(Box::new(leaf)), right, text.len());
+        self.len += text.len();
     }
 
-    fn new_leaf(text: *const u8, len: usize) -> Node {
-        Node::LeafNode(Lnode {
-            text: text,
-            len: len,
-        })
+    // Replace the text in `range` with `text`.
+    pub fn splice(&mut self, range: Range<usize>, text: &str) {
+        assert!(range.start <= range.end && range.end <= self.len,
+                "range {:?} out of bounds of rope (len {})", range, self.len);
+        self.remove(range.start, range.end);
+        self.insert_copy(range.start, text);
     }
 
-    fn len(&self) -> usize {
-        match *self {
-            Node::InnerNode(Inode { weight, ref right, .. }) => {
-                match *right {
-                    Some(ref r) => weight + r.len(),
-                    None => weight
-                }
+    // Swap the text in two ranges, e.g., to move a line up or down. The ranges
+    // may have different lengths, but must not overlap, and their ends must be
+    // char boundaries. The rope is unchanged if an error is returned.
+    pub fn swap(&mut self, a: Range<usize>, b: Range<usize>) -> Result<(), RopeError> {
+        for range in &[&a, &b] {
+            if range.start > range.end {
+                return Err(RopeError::ReversedRange((*range).clone()));
             }
-            Node::LeafNode(Lnode { len, .. }) => len,
+            self.check_offset(range.start)?;
+            self.check_offset(range.end)?;
+        }
+        if a.end > b.start && b.end > a.start {
+            return Err(RopeError::Overlap(a, b));
         }
+
+        let (first, second) = if a.start <= b.start { (a, b) } else { (b, a) };
+        let first_text = self.slice(first.clone()).to_string();
+        let second_text = self.slice(second.clone()).to_string();
+        // Edit the later range first, so the earlier one's offsets still hold.
+        self.splice(second, &first_text);
+        self.splice(first, &second_text);
+        Ok(())
     }
 
-    // Most of these methods are just doing dynamic dispatch, TODO use a macro
+    // Split the rope in two at byte offset `at`. `self` keeps the text before
+    // `at` and the text after is returned as a new rope.
+    pub fn split_off(&mut self, at: usize) -> Rope {
+        assert!(at <= self.len, "byte {} out of bounds of rope (len {})", at, self.len);
+        let mut result = Rope::new_with_segment_size(self.segment_size);
+        result.policy = self.policy;
+        result.insert(0, self.slice(at..self.len).to_string());
+        let len = self.len;
+        self.remove(at, len);
+        result
+    }
 
-    // precond: start < end
-    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
-        match *self {
-            Node::InnerNode(ref mut i) => i.remove(start, end),
-            Node::LeafNode(ref mut l) => l.remove(start, end),
-        }
+    // Split the rope in two at the start of `line`, returning the tail. If
+    // `line` is past the last line, the returned rope is empty.
+    pub fn split_off_at_line(&mut self, line: usize) -> Rope {
+        let at = if line < self.len_lines() {
+            self.line_to_byte(line)
+        } else {
+            self.len
+        };
+        self.split_off(at)
     }
 
-    fn insert(&mut self, node: Box<Node>, start: usize) -> NodeAction {
-        match *self {
-            Node::InnerNode(ref mut i) => i.insert(node, start),
-            Node::LeafNode(ref mut l) => l.insert(node, start),
+    // Replace the char at `at` with `c`. If the two chars have the same width,
+    // the char is overwritten in place, otherwise this is a `splice`.
+    pub fn replace_char(&mut self, at: usize, c: char) {
+        assert!(at < self.len, "byte {} out of bounds of rope (len {})", at, self.len);
+        let first = self.slice(at..at + 1).bytes().next();
+        let width = match first {
+            Some(b) if is_char_start(b) => utf8_char_width(b),
+            _ => panic!("byte {} is not the start of a char in rope (len {})", at, self.len),
+        };
+
+        let mut buf = [0; 4];
+        let new = c.encode_utf8(&mut buf);
+        if width == new.len() {
+            self.replace_str(at, new);
+        } else {
+            self.splice(at..at + width, new);
         }
     }
 
-    fn find_slice<'a>(&'a self, start: usize, end: usize, slice: &mut RopeSlice<'a>) {
-        match *self {
-            Node::InnerNode(ref i) => i.find_slice(start, end, slice),
-            Node::LeafNode(ref l) => l.find_slice(start, end, slice),
+    // Replace every line ending (`\n`, `\r\n`, or a lone `\r`) with `target`.
+    pub fn normalize_newlines(&mut self, target: LineEnding) {
+        let target = target.as_str();
+
+        // Build the new text in one pass.
+        let mut text = Vec::with_capacity(self.len);
+        let mut changed = false;
+        {
+            let slice = self.full_slice();
+            let mut bytes = slice.bytes().peekable();
+            while let Some(b) = bytes.next() {
+                let ending = match b {
+                    b'\r' => {
+                        if bytes.peek() == Some(&b'\n') {
+                            bytes.next();
+                            "\r\n"
+                        } else {
+                            "\r"
+                        }
+                    }
+                    b'\n' => "\n",
+                    _ => {
+                        text.push(b);
+                        continue;
+                    }
+                };
+                changed |= ending != target;
+                text.extend_from_slice(target.as_bytes());
+            }
         }
-    }
 
-    fn replace(&mut self, start: usize, new_str: &str) {
-        match *self {
-            Node::InnerNode(ref mut i) => i.replace(start, new_str),
-            Node::LeafNode(ref mut l) => l.replace(start, new_str),
+        if changed {
+            self.set_text(String::from_utf8(text).expect("non-utf8 char in rope"));
         }
     }
-}
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-enum NodeAction {
-    None,
-    Remove,
-    Adjust(isize), // Arg is the length of the old node - the length of the newly adjusted node.
-    Change(Box<Node>, isize) // Args are the new node and the change in length.
-}
+    // Call `f` with the index and text (without its newline) of each line.
+    // Where `f` returns `Some`, the line's text is replaced with the result.
+    pub fn map_lines<F>(&mut self, mut f: F)
+        where F: FnMut(usize, &str) -> Option<String>
+    {
+        let text = String::from_utf8(self.to_bytes()).expect("non-utf8 char in rope");
+        let mut result = String::with_capacity(text.len());
+        let mut changed = false;
+        // Splitting on '\n' gives the same lines as `lines`.
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                result.push('\n');
+            }
+            match f(i, line) {
+                Some(new) => {
+                    result.push_str(&new);
+                    changed = true;
+                }
+                None => result.push_str(line),
+            }
+        }
 
-impl Inode {
-    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
-        debug!("Inode::remove: {}, {}, {}", start, end, self.weight);
+        if changed {
+            self.set_text(result);
+        }
+    }
 
-        let left_action = if start <= self.weight {
-            if let Some(ref mut left) = self.left {
-                left.remove(start, end)
-            } else {
-                panic!();
-            }
-        } else {
-            NodeAction::None
+    // Remove blank lines (lines containing only whitespace) from the start and
+    // end of the rope. The newline ending the last non-blank line is kept. If
+    // every line is blank, the rope is cleared.
+    pub fn trim_blank_lines(&mut self) {
+        let (first, last) = {
+            let mut non_blank = self.lines().enumerate().filter(|(_, line)| {
+                !line.char_indices().all(|(_, c)| c.is_whitespace())
+            }).map(|(i, _)| i);
+            let first = non_blank.next();
+            (first, non_blank.last().or(first))
         };
 
-        let right_action = if end > self.weight {
-            if let Some(ref mut right) = self.right {
-                let start = if start < self.weight {
-                    0
-                } else {
-                    start - self.weight
-                };
-                right.remove(start, end - self.weight)
-            } else {
-                panic!();
+        let (start, end) = match (first, last) {
+            (Some(first), Some(last)) => {
+                let end = self.line_byte_range(last).unwrap().end;
+                (self.line_to_byte(first), ::std::cmp::min(end + 1, self.len))
             }
-        } else {
-            NodeAction::None
+            _ => (0, 0),
         };
+        self.truncate(end);
+        self.remove(0, start);
+    }
 
-
-        if left_action == NodeAction::Remove && right_action == NodeAction::Remove ||
-           left_action == NodeAction::Remove && self.right.is_none() ||
-           right_action == NodeAction::Remove && self.left.is_none() {
-            return NodeAction::Remove;
+    // Move `offset`, a byte offset into a rope before `edit`, to the
+    // corresponding offset after it, e.g., to keep a bookmark or diagnostic
+    // on the same text. Offsets up to the start of the edit are unchanged (so
+    // an offset where text is inserted stays before that text), offsets from
+    // the end of the removed text onwards are shifted, and offsets inside the
+    // removed text move to the start of the edit.
+    pub fn adjust_offset(offset: usize, edit: &Edit) -> usize {
+        let end = edit.start_byte + edit.removed_len;
+        if offset <= edit.start_byte {
+            offset
+        } else if offset >= end {
+            offset - edit.removed_len + edit.inserted_len
+        } else {
+            edit.start_byte
         }
+    }
 
-        if left_action == NodeAction::Remove {
-            return NodeAction::Change(self.right.clone().unwrap(),
-                                      -(self.weight as isize));
+    // Apply the changes in `hunks`, whose offsets all refer to the rope before
+    // any of them are applied. The hunks may be in any order, but must not
+    // overlap. The rope is unchanged if an error is returned.
+    pub fn apply_patch(&mut self, hunks: &[Hunk]) -> Result<(), PatchError> {
+        let mut order: Vec<usize> = (0..hunks.len()).collect();
+        order.sort_by_key(|&i| hunks[i].start_byte);
+
+        for (n, &i) in order.iter().enumerate() {
+            let start = hunks[i].start_byte;
+            let end = start.checked_add(hunks[i].removed_len).ok_or(PatchError::OutOfBounds(i))?;
+            if end > self.len || !self.is_char_boundary(start) || !self.is_char_boundary(end) {
+                return Err(PatchError::OutOfBounds(i));
+            }
+            if let Some(&next) = order.get(n + 1) {
+                if hunks[next].start_byte < end || hunks[next].start_byte == start {
+                    return Err(PatchError::Overlap(i, next));
+                }
+            }
         }
-        if right_action == NodeAction::Remove {
-            return NodeAction::Change(self.left.clone().unwrap(),
-                                      -(self.right.as_ref().map(|n| n.len()).unwrap() as isize));
+
+        // Apply the hunks back to front so that earlier offsets stay valid.
+        for &i in order.iter().rev() {
+            let hunk = &hunks[i];
+            self.splice(hunk.start_byte..hunk.start_byte + hunk.removed_len, &hunk.insert_text);
         }
+        Ok(())
+    }
 
-        let mut total_adj = 0;
-        if let NodeAction::Change(ref n, adj) = left_action {
-            self.left = Some(n.clone());
-            self.weight = (self.weight as isize + adj) as usize;
-            total_adj += adj;
+    // Sort the lines of the rope lexicographically. If the rope ends with a
+    // newline, the sorted text does too.
+    pub fn sort_lines(&mut self) {
+        let mut lines: Vec<String> = self.lines().map(|l| l.to_string()).collect();
+        let trailing_newline = lines.len() > 1 && lines.last().is_some_and(|l| l.is_empty());
+        if trailing_newline {
+            lines.pop();
         }
-        if let NodeAction::Change(ref n, adj) = right_action {
-            self.right = Some(n.clone());
-            total_adj += adj;
+        lines.sort();
+
+        let mut text = lines.join("\n");
+        if trailing_newline {
+            text.push('\n');
         }
+        self.set_text(text);
+    }
 
-        if let NodeAction::Adjust(adj) = left_action {
-            self.weight = (self.weight as isize + adj) as usize;
-            total_adj += adj;
+    // Remove lines which are the same as the line before, like `uniq`. The
+    // empty line following a trailing newline is not counted.
+    pub fn dedup_adjacent_lines(&mut self) {
+        let text = String::from_utf8(self.to_bytes()).expect("non-utf8 char in rope");
+        let (body, trailing_newline) = match text.strip_suffix('\n') {
+            Some(body) => (body, true),
+            None => (&text[..], false),
+        };
+
+        let mut result = String::with_capacity(text.len());
+        let mut prev = None;
+        let mut changed = false;
+        for line in body.split('\n') {
+            if prev == Some(line) {
+                changed = true;
+                continue;
+            }
+            if prev.is_some() {
+                result.push('\n');
+            }
+            result.push_str(line);
+            prev = Some(line);
         }
-        if let NodeAction::Adjust(adj) = right_action {
-            total_adj += adj;
+        if trailing_newline {
+            result.push('\n');
         }
 
-        return NodeAction::Adjust(total_adj);
+        if changed {
+            self.set_text(result);
+        }
     }
 
-    fn insert(&mut self, node: Box<Node>, start: usize) -> NodeAction {
-        let mut total_adj = 0;
-        if start <= self.weight {
-            let action = if let Some(ref mut left) = self.left {
-                left.insert(node, start)
+    // Replace the first occurrence of `from` with `to`. Returns false if
+    // `from` does not occur.
+    pub fn replace_first(&mut self, from: &str, to: &str) -> bool {
+        match self.find(from) {
+            Some(start) => {
+                self.splice(start..start + from.len(), to);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Keep only the lines for which `f(line_index, line_text)` returns true.
+    // A removed line is removed with its newline. The empty line following a
+    // trailing newline is not counted.
+    pub fn retain_lines<F>(&mut self, mut f: F)
+        where F: FnMut(usize, &str) -> bool
+    {
+        let text = String::from_utf8(self.to_bytes()).expect("non-utf8 char in rope");
+        let mut result = String::with_capacity(text.len());
+        let mut changed = false;
+        // Each piece is a line with its newline, if it has one.
+        for (i, piece) in text.split_inclusive('\n').enumerate() {
+            if f(i, piece.strip_suffix('\n').unwrap_or(piece)) {
+                result.push_str(piece);
             } else {
-                assert!(self.weight == 0);
-                let len = node.len() as isize;
-                NodeAction::Change(node, len)
-            };
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.set_text(result);
+        }
+    }
+
+    // As `splice`, but `chars` is a range of char indices.
+    pub fn replace_char_range(&mut self, chars: Range<usize>, new: &str) {
+        let start = self.char_to_byte(chars.start);
+        let end = self.char_to_byte(chars.end);
+        self.splice(start..end, new);
+    }
+
+    // Convert ASCII letters to upper case, in place. Non-ASCII chars are
+    // unchanged.
+    pub fn make_ascii_uppercase(&mut self) {
+        self.unshare();
+        // ASCII case changes never add or remove char starts, so the cached
+        // char counts stay valid.
+        self.root.for_each_leaf_mut(&mut |l| l.bytes_mut().make_ascii_uppercase());
+    }
+
+    // Convert ASCII letters to lower case, in place. Non-ASCII chars are
+    // unchanged.
+    pub fn make_ascii_lowercase(&mut self) {
+        self.unshare();
+        self.root.for_each_leaf_mut(&mut |l| l.bytes_mut().make_ascii_lowercase());
+    }
+
+    // The length of the rope in bytes. The same as `len`, but unambiguous
+    // next to `len_chars` and `len_lines`.
+    pub fn len_bytes(&self) -> usize {
+        self.len
+    }
+
+    // The number of chars in the rope.
+    pub fn len_chars(&self) -> usize {
+        self.root.char_len()
+    }
 
-            match action {
-                NodeAction::Change(n, adj) => {
-                    self.left = Some(n);
-                    self.weight += adj as usize;
-                    total_adj += adj;
+    // The number of lines in the rope. There is always at least one line, and
+    // a trailing newline starts a new, empty line.
+    pub fn len_lines(&self) -> usize {
+        self.count_bytes_to(self.len, |b| b == b'\n') + 1
+    }
+
+    // Build an index of the starts of lines in the rope, which answers the
+    // same queries as `byte_to_line` and `line_to_byte` in O(log n) rather
+    // than by scanning the rope. The index is not updated by later edits.
+    pub fn build_line_index(&self) -> LineIndex {
+        let mut starts = vec![0];
+        let mut offset = 0;
+        for chunk in self.full_slice().chunks() {
+            let newlines = chunk.iter().enumerate().filter(|&(_, &b)| b == b'\n');
+            starts.extend(newlines.map(|(i, _)| offset + i + 1));
+            offset += chunk.len();
+        }
+        LineIndex { starts, len: self.len }
+    }
+
+    // The length in bytes of the longest line, not including its newline (as
+    // in `lines`).
+    pub fn max_line_len(&self) -> usize {
+        let mut result = 0;
+        let mut line_len = 0;
+        for chunk in self.full_slice().chunks() {
+            for &b in chunk {
+                if b == b'\n' {
+                    result = ::std::cmp::max(result, line_len);
+                    line_len = 0;
+                } else {
+                    line_len += 1;
                 }
-                NodeAction::Adjust(adj) => {
-                    self.weight += adj as usize;
-                    total_adj += adj;
+            }
+        }
+        ::std::cmp::max(result, line_len)
+    }
+
+    // The line containing the byte at offset `byte`.
+    pub fn byte_to_line(&self, byte: usize) -> usize {
+        assert!(byte <= self.len, "byte {} out of bounds of rope (len {})", byte, self.len);
+        self.count_bytes_to(byte, |b| b == b'\n')
+    }
+
+    // The byte offset of the start of `line`. `line` may be one past the last
+    // line, in which case the length of the rope is returned.
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        if line == 0 {
+            return 0;
+        }
+
+        let mut seen = 0;
+        let mut offset = 0;
+        let slice = self.full_slice();
+        for chunk in slice.chunks() {
+            for (i, &b) in chunk.iter().enumerate() {
+                if b == b'\n' {
+                    seen += 1;
+                    if seen == line {
+                        return offset + i + 1;
+                    }
                 }
-                _ => panic!("Unexpected action"),
             }
-        } else {
-            let action = if let Some(ref mut right) = self.right {
-                assert!(start >= self.weight);
-                right.insert(node, start - self.weight)
-            } else {
-                let len = node.len() as isize;
-                NodeAction::Change(node, len)
-            };
+            offset += chunk.len();
+        }
+
+        assert!(line == seen + 1, "line {} out of bounds of rope ({} lines)", line, seen + 1);
+        self.len
+    }
 
-            match action {
-                NodeAction::Change(n, adj) => {
-                    self.right = Some(n);
-                    total_adj += adj;
+    // The byte offset of column `col_bytes` (in bytes) of `line`, or `None` if
+    // there is no such position. The column may be the length of the line.
+    pub fn byte_at(&self, line: usize, col_bytes: usize) -> Option<usize> {
+        let slice = self.full_slice();
+        let mut cur_line = 0;
+        let mut line_start = 0;
+        for (i, b) in slice.bytes().enumerate() {
+            if cur_line == line && i == line_start + col_bytes {
+                return Some(i);
+            }
+            if b == b'\n' {
+                if cur_line == line {
+                    return None;
                 }
-                NodeAction::Adjust(adj) => total_adj += adj,
-                _ => panic!("Unexpected action"),
+                cur_line += 1;
+                line_start = i + 1;
             }
         }
 
-        NodeAction::Adjust(total_adj)
+        if cur_line == line && line_start + col_bytes == self.len {
+            Some(self.len)
+        } else {
+            None
+        }
     }
 
-    fn find_slice<'a>(&'a self, start: usize, end: usize, slice: &mut RopeSlice<'a>) {
-        debug!("Inode::find_slice: {}, {}, {}", start, end, self.weight);
-        if start < self.weight {
-            self.left.as_ref().unwrap().find_slice(start, end, slice);
+    // The char at column `col` (in chars) of `line`, or `None` if there is no
+    // such char. Newlines are not considered part of a line.
+    pub fn char_at(&self, line: usize, col: usize) -> Option<char> {
+        if line >= self.len_lines() {
+            return None;
         }
-        if end > self.weight {
-            let start = if start < self.weight {
-                0
-            } else {
-                start - self.weight
-            };
-            self.right.as_ref().unwrap().find_slice(start, end - self.weight, slice)
+
+        let slice = self.slice(self.line_to_byte(line)..self.len);
+        let result = slice.char_indices()
+                          .map(|(_, c)| c)
+                          .take_while(|&c| c != '\n')
+                          .nth(col);
+        result
+    }
+
+    // A slice covering `line_count` lines, starting at `first_line`, including
+    // their newlines. Lines past the end of the rope are ignored.
+    pub fn viewport(&self, first_line: usize, line_count: usize) -> RopeSlice<'_> {
+        let lines = self.len_lines();
+        let first_line = ::std::cmp::min(first_line, lines);
+        let end_line = ::std::cmp::min(first_line.saturating_add(line_count), lines);
+        self.slice(self.line_to_byte(first_line)..self.line_to_byte(end_line))
+    }
+
+    // The first line of the rope, not including its newline.
+    pub fn first_line(&self) -> RopeSlice<'_> {
+        self.slice(self.line_byte_range(0).unwrap())
+    }
+
+    // The last line of the rope, not including its newline. The empty line
+    // following a trailing newline is not counted, so the last line of
+    // "a\nb\n" is "b".
+    pub fn last_line(&self) -> RopeSlice<'_> {
+        let mut line = self.len_lines() - 1;
+        if line > 0 && self.line_to_byte(line) == self.len {
+            line -= 1;
         }
+        self.slice(self.line_byte_range(line).unwrap())
     }
 
-    fn replace(&mut self, start: usize, new_str: &str) {
-        debug!("Inode::replace: {}, {}, {}", start, new_str, self.weight);
-        let end = start + new_str.len();
-        if start < self.weight {
-            if let Some(ref mut left) = self.left {
-                left.replace(start, &new_str[..::std::cmp::min(self.weight-start, new_str.len())]);
-            } else {
-                panic!();
-            }
+    // The slice covering the last `n` lines of the rope, or the whole rope if
+    // it has no more than `n` lines. As in `last_line`, the empty line
+    // following a trailing newline is not counted (but the newline is part of
+    // the slice). The rope is scanned from the end, so this is cheap for a
+    // long log when `n` is small.
+    pub fn tail(&self, n: usize) -> RopeSlice<'_> {
+        if n == 0 {
+            return self.slice(self.len..self.len);
         }
-        if end > self.weight {
-            let (start, offset) = if start < self.weight {
-                (0, self.weight - start)
-            } else {
-                (start - self.weight, 0)
+
+        let trailing_newline = self.len > 0 &&
+                               self.slice(self.len - 1..self.len).bytes().next() == Some(b'\n');
+        let end = if trailing_newline { self.len - 1 } else { self.len };
+        let start = self.slice_to(end)
+                        .bytes()
+                        .rev()
+                        .enumerate()
+                        .filter(|&(_, b)| b == b'\n')
+                        .nth(n - 1)
+                        .map_or(0, |(i, _)| end - i);
+        self.slice(start..self.len)
+    }
+
+    // The byte range of `line`, not including its newline, or `None` if there
+    // is no such line.
+    pub fn line_byte_range(&self, line: usize) -> Option<Range<usize>> {
+        if line >= self.len_lines() {
+            return None;
+        }
+
+        let start = self.line_to_byte(line);
+        let end = self.find_byte_from(start, b'\n').unwrap_or(self.len);
+        Some(start..end)
+    }
+
+    // The byte range of the paragraph containing `byte`, i.e., the run of
+    // non-blank lines around it, not including the newline which ends the
+    // last of them. As in `trim_blank_lines`, a blank line is one containing
+    // only whitespace. If `byte` is on a blank line, the result is the empty
+    // range `byte..byte`.
+    pub fn paragraph_at(&self, byte: usize) -> Range<usize> {
+        assert!(byte <= self.len, "byte {} out of bounds of rope (len {})", byte, self.len);
+
+        let is_blank = |range: Range<usize>| {
+            self.slice(range).char_indices().all(|(_, c)| c.is_whitespace())
+        };
+        // The line containing `at`, found by searching outwards from `at` so
+        // that only the lines of the paragraph are read.
+        let line_around = |at: usize| {
+            let start = self.rfind_from("\n", at).map_or(0, |i| i + 1);
+            let end = self.find_byte_from(at, b'\n').unwrap_or(self.len);
+            start..end
+        };
+
+        let mut result = line_around(byte);
+        if is_blank(result.clone()) {
+            return byte..byte;
+        }
+        while result.start > 0 {
+            let prev = line_around(result.start - 1);
+            if is_blank(prev.clone()) {
+                break;
+            }
+            result.start = prev.start;
+        }
+        while result.end < self.len {
+            let next = line_around(result.end + 1);
+            if is_blank(next.clone()) {
+                break;
+            }
+            result.end = next.end;
+        }
+        result
+    }
+
+    // The column of `byte` in its line, in UTF-16 code units. This is the
+    // character offset used by the Language Server Protocol.
+    pub fn utf16_col(&self, byte: usize) -> usize {
+        let start = self.line_to_byte(self.byte_to_line(byte));
+        let slice = self.slice(start..byte);
+        let result = slice.char_indices().map(|(_, c)| c.len_utf16()).sum();
+        result
+    }
+
+    // The length of the text in `range`, in UTF-16 code units, e.g., for
+    // reporting the length of a changed region to a Language Server Protocol
+    // client. Both ends of `range` must be char boundaries.
+    pub fn utf16_len_in(&self, range: Range<usize>) -> usize {
+        self.assert_char_range(&range);
+        let slice = self.slice(range);
+        let result = slice.char_indices().map(|(_, c)| c.len_utf16()).sum();
+        result
+    }
+
+    // Convert a byte range to a range of UTF-16 code units from the start of
+    // the rope, in one pass. Both ends of `bytes` must be char boundaries.
+    pub fn utf16_range(&self, bytes: Range<usize>) -> Range<usize> {
+        self.assert_char_range(&bytes);
+        let mut start = None;
+        let mut offset = 0;
+        for (i, c) in self.slice_to(bytes.end).char_indices() {
+            if i == bytes.start {
+                start = Some(offset);
+            }
+            offset += c.len_utf16();
+        }
+        start.unwrap_or(offset)..offset
+    }
+
+    // Panic unless `range` is in bounds and both its ends are char
+    // boundaries.
+    fn assert_char_range(&self, range: &Range<usize>) {
+        assert!(range.start <= range.end && range.end <= self.len,
+                "range {:?} out of bounds of rope (len {})", range, self.len);
+        for &byte in &[range.start, range.end] {
+            assert!(self.is_char_boundary(byte),
+                    "byte {} is not a char boundary of rope (len {})", byte, self.len);
+        }
+    }
+
+    // The char starting at `u16_offset` UTF-16 code units from the start of
+    // the rope, or `None` if the offset is past the end or inside a surrogate
+    // pair.
+    pub fn char_at_utf16(&self, u16_offset: usize) -> Option<char> {
+        let mut offset = 0;
+        for (_, c) in self.char_indices() {
+            if offset == u16_offset {
+                return Some(c);
+            }
+            offset += c.len_utf16();
+            if offset > u16_offset {
+                return None;
+            }
+        }
+        None
+    }
+
+    // As `byte_to_line`, but `char_idx` is an index in chars.
+    pub fn char_to_line(&self, char_idx: usize) -> usize {
+        self.byte_to_line(self.char_to_byte(char_idx))
+    }
+
+    // As `line_to_byte`, but returns an index in chars.
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.byte_to_char(self.line_to_byte(line))
+    }
+
+    // Iterate over the chars in the rope and their byte offsets, from the end
+    // of the rope to the start.
+    pub fn chars_rev(&self) -> RopeCharsRev<'_> {
+        RopeCharsRev::new(self.full_slice(), self.len)
+    }
+
+    // As `chars_rev`, but only iterates over the chars in `range`.
+    pub fn chars_rev_in(&self, range: Range<usize>) -> RopeCharsRev<'_> {
+        assert!(range.start <= range.end && range.end <= self.len,
+                "range {:?} out of bounds of rope (len {})", range, self.len);
+        RopeCharsRev::new(self.slice(range.clone()), range.end)
+    }
+
+    // As `chars_rev`, but yields `(offset, char)` like `str::char_indices`.
+    pub fn char_indices_rev(&self) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.chars_rev().map(|(c, b)| (b, c))
+    }
+
+    // Iterate over the chars in the rope with their byte offsets and the index
+    // of the leaf each starts in (counting leaves from the start of the rope).
+    // Useful for debugging and for tools which care about how the rope is
+    // segmented.
+    pub fn chars_with_leaf(&self) -> impl Iterator<Item = (char, usize, usize)> + '_ {
+        let slice = self.full_slice();
+        let mut bytes = (0..slice.nodes.len()).flat_map(move |i| {
+            slice.chunk(i).iter().map(move |&b| (b, i))
+        });
+        let mut offset = 0;
+        ::std::iter::from_fn(move || {
+            let (first, leaf) = bytes.next()?;
+            let c = decode_char(first, &mut (&mut bytes).map(|(b, _)| b));
+            let start = offset;
+            offset += c.len_utf8();
+            Some((c, start, leaf))
+        })
+    }
+
+    // The chars of the rope with their byte offsets. Unlike `chars`, this
+    // reads a char which is split between leaves correctly.
+    fn char_indices(&self) -> impl Iterator<Item = (usize, char)> + '_ {
+        let slice = self.full_slice();
+        let mut bytes = (0..slice.nodes.len()).flat_map(move |i| slice.chunk(i).iter().cloned());
+        let mut offset = 0;
+        ::std::iter::from_fn(move || {
+            let first = bytes.next()?;
+            let c = decode_char(first, &mut bytes);
+            let start = offset;
+            offset += c.len_utf8();
+            Some((start, c))
+        })
+    }
+
+    // The number of chars before the first char for which `f` returns true,
+    // or the number of chars in the rope if there is no such char.
+    pub fn chars_until<F>(&self, mut f: F) -> usize
+        where F: FnMut(char) -> bool
+    {
+        let slice = self.full_slice();
+        let result = slice.char_indices().take_while(|&(_, c)| !f(c)).count();
+        result
+    }
+
+    // The number of chars in the rope for which `f` returns true.
+    pub fn count_chars<F>(&self, mut f: F) -> usize
+        where F: FnMut(char) -> bool
+    {
+        let slice = self.full_slice();
+        let result = slice.char_indices().filter(|&(_, c)| f(c)).count();
+        result
+    }
+
+    // Iterate over the byte offset of each occurrence of `c` in the rope.
+    pub fn char_offsets(&self, c: char) -> impl Iterator<Item = usize> + '_ {
+        self.char_indices().filter(move |&(_, ch)| ch == c).map(|(b, _)| b)
+    }
+
+    // Iterate over each run of `n` consecutive chars in the rope, like
+    // `slice::windows`.
+    pub fn char_windows(&self, n: usize) -> impl Iterator<Item = Vec<char>> + '_ {
+        assert!(n > 0, "window size must be non-zero");
+
+        let mut chars = self.char_indices().map(|(_, c)| c);
+        let mut window: VecDeque<char> = chars.by_ref().take(n - 1).collect();
+        ::std::iter::from_fn(move || {
+            let c = chars.next()?;
+            if window.len() == n {
+                window.pop_front();
+            }
+            window.push_back(c);
+            Some(window.iter().cloned().collect())
+        })
+    }
+
+    // The byte range of the word at `byte`, e.g., for selecting a word on a
+    // double click. A word is a run of alphanumeric chars and underscores.
+    // If `byte` is inside a word, or at its start or end, that word is
+    // returned. Otherwise (i.e., `byte` is between two chars which are both
+    // whitespace or punctuation) the result is the empty range `byte..byte`.
+    pub fn word_at(&self, byte: usize) -> Range<usize> {
+        assert!(byte <= self.len, "byte {} out of bounds of rope (len {})", byte, self.len);
+        assert!(self.is_char_boundary(byte),
+                "byte {} is not a char boundary of rope (len {})", byte, self.len);
+
+        fn is_word_char(c: char) -> bool {
+            c.is_alphanumeric() || c == '_'
+        }
+
+        let start = self.chars_rev_in(0..byte)
+                        .take_while(|&(c, _)| is_word_char(c))
+                        .last()
+                        .map_or(byte, |(_, b)| b);
+        let end = self.slice_from(byte)
+                      .char_indices()
+                      .take_while(|&(_, c)| is_word_char(c))
+                      .last()
+                      .map_or(byte, |(i, c)| byte + i + c.len_utf8());
+        start..end
+    }
+
+    // Whether any leaf starts part way through a char. If not, the text of
+    // every leaf is valid UTF-8 and can be read as a `&str`.
+    pub fn has_split_chars(&self) -> bool {
+        let slice = self.full_slice();
+        let result = slice.chunks().any(|c| c.first().is_some_and(|&b| !is_char_start(b)));
+        result
+    }
+
+    // Merge leaves so that no leaf starts part way through a char, copying the
+    // text of merged leaves into a new buffer. Afterwards `has_split_chars`
+    // is false (if the rope is valid UTF-8).
+    pub fn heal_boundaries(&mut self) {
+        if !self.has_split_chars() {
+            return;
+        }
+
+        // Group the leaves into runs which each start on a char boundary.
+        let mut runs: Vec<Range<usize>> = vec![];
+        for (i, chunk) in self.full_slice().chunks().enumerate() {
+            let split = chunk.first().is_some_and(|&b| !is_char_start(b));
+            match runs.last_mut() {
+                Some(run) if split => run.end = i + 1,
+                _ => runs.push(i..i + 1),
+            }
+        }
+        self.merge_leaf_runs(runs);
+    }
+
+    // Rebuild the tree so that it is balanced. The leaves are kept as they are,
+    // so no text is copied; only the inner nodes are replaced. Every edit can
+    // make the tree deeper, so a rope which has been edited many times may
+    // become a long chain of nodes, making each operation on it O(n). See
+    // also `insert_balanced`, and `coalesce` for merging small leaves.
+    pub fn balance(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let leaves: Vec<Lnode> = self.root.leaves().into_iter().cloned().collect();
+        self.root = Node::from_leaves(&leaves);
+        // The leftmost leaf may no longer be the root's left child.
+        self.front = None;
+    }
+
+    // Merge runs of adjacent leaves into leaves of up to `segment_size` bytes,
+    // copying the text of merged leaves into new buffers, and rebuild the tree
+    // balanced. Leaves which are already large are left where they are. Old
+    // storage is not freed (see `shrink_to_fit`). See `segment_count` and
+    // `average_segment_len` for deciding when this is worthwhile.
+    pub fn coalesce(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let mut runs: Vec<Range<usize>> = vec![];
+        let mut run_len = 0;
+        for (i, chunk) in self.full_slice().chunks().enumerate() {
+            match runs.last_mut() {
+                Some(run) if run_len + chunk.len() <= self.segment_size => {
+                    run.end = i + 1;
+                    run_len += chunk.len();
+                }
+                _ => {
+                    runs.push(i..i + 1);
+                    run_len = chunk.len();
+                }
+            }
+        }
+        self.merge_leaf_runs(runs);
+    }
+
+    // Rebuild the tree balanced, replacing each run of leaves in `runs`
+    // (which must cover every leaf, in order) with a single leaf. The text of
+    // runs of more than one leaf is copied into a new buffer.
+    fn merge_leaf_runs(&mut self, runs: Vec<Range<usize>>) {
+        let mut leaves = vec![];
+        let mut buffers = vec![];
+        {
+            let slice = self.full_slice();
+            for run in runs {
+                if run.len() == 1 {
+                    leaves.push(slice.nodes[run.start].clone());
+                    continue;
+                }
+                let buf: Vec<u8> = run.flat_map(|i| slice.chunk(i).iter().cloned()).collect();
+                leaves.push(Lnode::new(buf.as_ptr(), buf.len()));
+                buffers.push(buf);
+            }
+        }
+
+        self.storage.extend(buffers);
+        self.root = Node::from_leaves(&leaves);
+        // The leftmost leaf may have been merged into a new buffer.
+        self.front = None;
+    }
+
+    // The number of leaves in the rope. Many short leaves (see
+    // `average_segment_len`) make reads slower and the tree deeper; `coalesce`
+    // and `flatten` merge them.
+    pub fn segment_count(&self) -> usize {
+        self.root.leaves().len()
+    }
+
+    // The mean length in bytes of the rope's leaves, or 0 if it is empty.
+    pub fn average_segment_len(&self) -> f64 {
+        match self.segment_count() {
+            0 => 0.0,
+            n => self.len as f64 / n as f64,
+        }
+    }
+
+    // Iterate over the offsets of the char boundaries in `range`, not including
+    // `range.end`.
+    pub fn boundaries_in(&self, range: Range<usize>) -> impl Iterator<Item = usize> + '_ {
+        assert!(range.start <= range.end && range.end <= self.len,
+                "range {:?} out of bounds of rope (len {})", range, self.len);
+        let start = range.start;
+        let slice = self.slice(range);
+        (0..slice.nodes.len()).flat_map(move |i| slice.chunk(i).iter().cloned())
+                              .enumerate()
+                              .filter(|&(_, b)| is_char_start(b))
+                              .map(move |(i, _)| start + i)
+    }
+
+    // Iterate over the bytes of the rope in chunks of `chunk` bytes, with a
+    // shorter final chunk if the length is not a multiple of `chunk`. Chunks
+    // are borrowed from the rope, except where a chunk crosses a leaf
+    // boundary and must be copied.
+    pub fn byte_chunks_exact(&self, chunk: usize) -> impl Iterator<Item = Cow<'_, [u8]>> {
+        assert!(chunk > 0, "chunk size must be non-zero");
+
+        let slice = self.full_slice();
+        let mut node = 0;
+        let mut offset = 0;
+        ::std::iter::from_fn(move || {
+            while node < slice.nodes.len() && offset == slice.chunk(node).len() {
+                node += 1;
+                offset = 0;
+            }
+            if node == slice.nodes.len() {
+                return None;
+            }
+
+            let cur = slice.chunk(node);
+            if cur.len() - offset >= chunk || node == slice.nodes.len() - 1 {
+                let end = ::std::cmp::min(offset + chunk, cur.len());
+                let result = &cur[offset..end];
+                offset = end;
+                return Some(Cow::Borrowed(result));
+            }
+
+            let mut buf = Vec::with_capacity(chunk);
+            while buf.len() < chunk && node < slice.nodes.len() {
+                let cur = slice.chunk(node);
+                let n = ::std::cmp::min(chunk - buf.len(), cur.len() - offset);
+                buf.extend_from_slice(&cur[offset..offset + n]);
+                offset += n;
+                if offset == cur.len() {
+                    node += 1;
+                    offset = 0;
+                }
+            }
+            Some(Cow::Owned(buf))
+        })
+    }
+
+    // Iterate over the text of each leaf which overlaps `range`, clipped to
+    // `range`, with the offsets of that text in the rope. Panics if a leaf
+    // boundary splits a char.
+    pub fn segments_in(&self, range: Range<usize>) -> impl Iterator<Item = (&str, Range<usize>)> {
+        let slice = self.slice(range.clone());
+        let mut offset = range.start;
+        (0..slice.nodes.len()).map(move |i| {
+            let chunk = slice.chunk(i);
+            let start = offset;
+            offset += chunk.len();
+            (::std::str::from_utf8(chunk).expect("leaf boundary splits a char"), start..offset)
+        })
+    }
+
+    // The text of each leaf, in order, e.g., for writing the whole rope with
+    // `Write::write_vectored` without copying it into one buffer first.
+    // Panics if a leaf boundary splits a char (see `heal_boundaries`).
+    pub fn as_slices(&self) -> Vec<&str> {
+        self.segments_in(0..self.len).map(|(s, _)| s).collect()
+    }
+
+    // Iterate over the text of each leaf, from the last leaf to the first.
+    // Panics if a leaf boundary splits a char (see `heal_boundaries`).
+    pub fn chunks_rev(&self) -> impl Iterator<Item = &str> {
+        let slice = self.full_slice();
+        (0..slice.nodes.len()).rev().map(move |i| {
+            ::std::str::from_utf8(slice.chunk(i)).expect("leaf boundary splits a char")
+        })
+    }
+
+    // Split the first line of the rope (not including its newline) into
+    // fields of the given widths in bytes, for parsing fixed-width records. If
+    // the line runs out, the last field is cut short and any remaining fields
+    // are omitted. A field which would end inside a char ends before it
+    // instead, and the char starts the next field.
+    pub fn column_slices(&self, widths: &[usize]) -> Vec<RopeSlice<'_>> {
+        // There is always a first line, even in an empty rope.
+        let line_end = self.find_byte_from(0, b'\n').unwrap_or(self.len);
+        let mut result = vec![];
+        let mut start = 0;
+        for &width in widths {
+            if start >= line_end {
+                break;
+            }
+            let end = self.clamp_to_boundary(::std::cmp::min(start + width, line_end));
+            result.push(self.slice(start..end));
+            start = end;
+        }
+        result
+    }
+
+    // Iterate over the lines in the rope, without their newlines. Yields
+    // `len_lines()` lines, so a trailing newline is followed by an empty line.
+    pub fn lines(&self) -> RopeLines<'_> {
+        RopeLines {
+            rope: self,
+            next: Some(0),
+        }
+    }
+
+    // The slice from byte `start` to the end of the rope.
+    pub fn slice_from(&self, start: usize) -> RopeSlice<'_> {
+        assert!(start <= self.len, "byte {} out of bounds of rope (len {})", start, self.len);
+        self.slice(start..self.len)
+    }
+
+    // The slice from the start of the rope to byte `end`.
+    pub fn slice_to(&self, end: usize) -> RopeSlice<'_> {
+        assert!(end <= self.len, "byte {} out of bounds of rope (len {})", end, self.len);
+        self.slice(0..end)
+    }
+
+    // A slice covering the chars in `chars`, or `None` if the range is not
+    // within the rope.
+    pub fn get_char_slice(&self, chars: Range<usize>) -> Option<RopeSlice<'_>> {
+        if chars.start > chars.end || chars.end > self.byte_to_char(self.len) {
+            return None;
+        }
+
+        let start = self.char_to_byte(chars.start);
+        let end = self.char_to_byte(chars.end);
+        Some(self.slice(start..end))
+    }
+
+    // Insert a newline at `at`, followed by the indentation (leading spaces
+    // and tabs) of the line containing `at`. Returns the offset just after the
+    // inserted text, i.e., the new cursor position. Indentation after `at` is
+    // not copied.
+    pub fn insert_smart_newline(&mut self, at: usize) -> usize {
+        let line_start = self.line_to_byte(self.byte_to_line(at));
+        let mut text = "\n".to_string();
+        text.extend(self.slice(line_start..at)
+                        .bytes()
+                        .take_while(|&b| b == b' ' || b == b'\t')
+                        .map(|b| b as char));
+
+        let len = text.len();
+        self.insert(at, text);
+        at + len
+    }
+
+    // The number of bytes taken up by the chars in `chars`.
+    pub fn byte_span(&self, chars: Range<usize>) -> usize {
+        assert!(chars.start <= chars.end, "bad char range {:?}", chars);
+
+        let slice = self.full_slice();
+        let mut count = 0;
+        let mut span = 0;
+        let starts = slice.bytes().filter(|&b| is_char_start(b));
+        for b in starts.skip(chars.start).take(chars.end - chars.start) {
+            count += 1;
+            span += utf8_char_width(b);
+        }
+        assert!(count == chars.end - chars.start, "char range {:?} out of bounds of rope", chars);
+        span
+    }
+
+    // Whether the rope is empty or contains only whitespace.
+    pub fn is_blank(&self) -> bool {
+        let slice = self.full_slice();
+        let result = slice.char_indices().all(|(_, c)| c.is_whitespace());
+        result
+    }
+
+    // The longest prefix shared by all lines in `lines`. Useful for finding the
+    // common indentation or comment leader of a block.
+    pub fn lines_common_prefix(&self, lines: Range<usize>) -> String {
+        assert!(lines.start <= lines.end && lines.end <= self.len_lines(),
+                "line range {:?} out of bounds of rope ({} lines)", lines, self.len_lines());
+
+        let mut lines = self.lines().skip(lines.start).take(lines.end - lines.start);
+        let mut prefix = match lines.next() {
+            Some(line) => line.to_string(),
+            None => return String::new(),
+        };
+        for line in lines {
+            let mut common = 0;
+            for ((i, a), (_, b)) in prefix.char_indices().zip(line.char_indices()) {
+                if a != b {
+                    break;
+                }
+                common = i + a.len_utf8();
+            }
+            prefix.truncate(common);
+        }
+        prefix
+    }
+
+    // The parts of the line containing `byte` before and after `byte`. Neither
+    // part includes a newline.
+    pub fn split_line_at(&self, byte: usize) -> (RopeSlice<'_>, RopeSlice<'_>) {
+        let line_start = self.line_to_byte(self.byte_to_line(byte));
+        let line_end = self.find_byte_from(byte, b'\n').unwrap_or(self.len);
+        (self.slice(line_start..byte), self.slice(byte..line_end))
+    }
+
+    // Fold `f` over the bytes of the rope, in order.
+    pub fn fold_bytes<B, F>(&self, init: B, f: F) -> B
+        where F: FnMut(B, u8) -> B
+    {
+        self.full_slice().bytes().fold(init, f)
+    }
+
+    // A hash of the text in the rope, for cheaply detecting changes. The
+    // digest depends only on the text, not on how it is split into leaves.
+    #[cfg(feature = "digest")]
+    pub fn digest(&self) -> u64 {
+        use std::hash::Hasher;
+
+        let mut hasher = ::twox_hash::XxHash64::with_seed(0);
+        for chunk in self.full_slice().chunks() {
+            hasher.write(chunk);
+        }
+        hasher.finish()
+    }
+
+    // Map `f` over the chars of the rope.
+    pub fn map_chars<B, F>(&self, mut f: F) -> Vec<B>
+        where F: FnMut(char) -> B
+    {
+        let mut result = Vec::with_capacity(self.len_chars());
+        result.extend(self.char_indices().map(|(_, c)| f(c)));
+        result
+    }
+
+    // A slice for each line in the rope, without newlines. See `lines`.
+    pub fn line_slices(&self) -> Vec<RopeSlice<'_>> {
+        let mut result = Vec::with_capacity(self.len_lines());
+        result.extend(self.lines());
+        result
+    }
+
+    // The number of extended grapheme clusters in `range`.
+    #[cfg(feature = "grapheme")]
+    pub fn grapheme_count_in(&self, range: Range<usize>) -> usize {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        self.slice(range).to_string().graphemes(true).count()
+    }
+
+    // Iterate over the extended grapheme clusters in the rope, with the byte
+    // offset of the start of each. Clusters are found leaf by leaf; a cluster
+    // is only copied if it crosses a leaf boundary.
+    #[cfg(feature = "grapheme")]
+    pub fn grapheme_indices(&self) -> impl Iterator<Item = (usize, Cow<'_, str>)> {
+        use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+
+        let pieces = self.str_pieces();
+        let len = self.len;
+        let mut piece = 0;
+        let mut cursor = None;
+        let mut start = 0;
+        ::std::iter::from_fn(move || {
+            let end = loop {
+                let (s, ref t) = *pieces.get(piece)?;
+                // Each piece gets a new cursor, which first checks for a
+                // boundary at the start of the piece. A cursor which is
+                // carried across pieces counts regional indicators twice
+                // when it asks for context.
+                let c = cursor.get_or_insert_with(|| GraphemeCursor::new(s, len, true));
+                let result = if s > start && c.cur_cursor() == s {
+                    match c.is_boundary(t, s) {
+                        Ok(true) => Ok(Some(s)),
+                        Ok(false) => c.next_boundary(t, s),
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    c.next_boundary(t, s)
+                };
+                match result {
+                    Ok(end) => break end?,
+                    Err(GraphemeIncomplete::NextChunk) => {
+                        piece += 1;
+                        cursor = None;
+                    }
+                    Err(GraphemeIncomplete::PreContext(at)) => {
+                        let i = pieces.partition_point(|&(s, ref t)| s + t.len() < at);
+                        let (s, ref t) = pieces[i];
+                        c.provide_context(&t[..at - s], s);
+                    }
+                    Err(e) => panic!("unexpected grapheme error {:?}", e),
+                }
             };
-            if let Some(ref mut right) = self.right {
-                right.replace(start, &new_str[offset..]);
+
+            // Stitch together the pieces which the cluster spans.
+            let first = pieces.partition_point(|&(s, ref t)| s + t.len() <= start);
+            let (s, ref t) = pieces[first];
+            let cluster = match *t {
+                Cow::Borrowed(t) if end <= s + t.len() => Cow::Borrowed(&t[start - s..end - s]),
+                _ => {
+                    let mut text = String::with_capacity(end - start);
+                    for &(s, ref t) in pieces[first..].iter().take_while(|&&(s, _)| s < end) {
+                        let to = ::std::cmp::min(end - s, t.len());
+                        text.push_str(&t[start.saturating_sub(s)..to]);
+                    }
+                    Cow::Owned(text)
+                }
+            };
+            let result = (start, cluster);
+            start = end;
+            Some(result)
+        })
+    }
+
+    // The text of the rope as consecutive non-empty strs, each with its byte
+    // offset. Each leaf is one str, except that a char split between leaves
+    // is copied into a str of its own.
+    #[cfg(feature = "grapheme")]
+    fn str_pieces(&self) -> Vec<(usize, Cow<'_, str>)> {
+        let mut result = vec![];
+        // A split char, its offset, and how many of its bytes are missing.
+        let mut split: (Vec<u8>, usize, usize) = (vec![], 0, 0);
+        let mut offset = 0;
+        for chunk in self.full_slice().chunks() {
+            let take = ::std::cmp::min(split.2, chunk.len());
+            if take > 0 {
+                split.0.extend_from_slice(&chunk[..take]);
+                split.2 -= take;
+                if split.2 == 0 {
+                    let text = String::from_utf8(::std::mem::take(&mut split.0));
+                    result.push((split.1, Cow::Owned(text.expect("non-utf8 char in rope"))));
+                }
+            }
+
+            // Find a char at the end of the leaf which is not complete.
+            let rest = &chunk[take..];
+            let tail = (0..rest.len()).rev().take(4).find(|&i| is_char_start(rest[i]));
+            let whole = match tail {
+                Some(i) if i + utf8_char_width(rest[i]) > rest.len() => {
+                    let missing = i + utf8_char_width(rest[i]) - rest.len();
+                    split = (rest[i..].to_vec(), offset + take + i, missing);
+                    &rest[..i]
+                }
+                _ => rest,
+            };
+            if !whole.is_empty() {
+                let text = ::std::str::from_utf8(whole).expect("non-utf8 char in rope");
+                result.push((offset + take, Cow::Borrowed(text)));
+            }
+            offset += chunk.len();
+        }
+        result
+    }
+
+    // The width of the first line of the rope in terminal columns. Tabs
+    // advance to the next multiple of `tab_width`, East Asian wide chars take
+    // two columns, and zero-width and control chars take none.
+    #[cfg(feature = "width")]
+    pub fn display_width(&self, tab_width: usize) -> usize {
+        use unicode_width::UnicodeWidthChar;
+
+        assert!(tab_width > 0, "tab width must be non-zero");
+        let end = self.find_byte_from(0, b'\n').unwrap_or(self.len);
+        let slice = self.slice(0..end);
+        let mut width = 0;
+        for (_, c) in slice.char_indices() {
+            if c == '\t' {
+                width += tab_width - width % tab_width;
             } else {
-                panic!();
+                width += c.width().unwrap_or(0);
+            }
+        }
+        width
+    }
+
+    // The byte offset of the first occurrence of `needle` in the rope, or
+    // `None` if it does not occur.
+    pub fn find(&self, needle: &str) -> Option<usize> {
+        self.find_from(needle, 0)
+    }
+
+    // The byte offset of the first occurrence of `needle` which starts at or
+    // after `start`, or `None` if there is none. For "find next", pass the end
+    // of the previous match as `start`.
+    pub fn find_from(&self, needle: &str, start: usize) -> Option<usize> {
+        self.slice_from(start).find(needle.as_bytes()).map(|i| start + i)
+    }
+
+    // The byte offset of the last occurrence of `needle` which ends at or
+    // before `end`, or `None` if there is none. For "find previous", pass the
+    // start of the previous match as `end`.
+    pub fn rfind_from(&self, needle: &str, end: usize) -> Option<usize> {
+        self.slice_to(end).rfind(needle.as_bytes())
+    }
+
+    // Iterate over the lines which contain `needle` (see `lines`), with their
+    // indices.
+    pub fn lines_matching<'a>(&'a self, needle: &'a str)
+                              -> impl Iterator<Item = (usize, RopeSlice<'a>)> + 'a {
+        self.lines().enumerate().filter(move |(_, line)| line.find(needle.as_bytes()).is_some())
+    }
+
+    // Split the rope around the first occurrence of `delim`, like
+    // `str::split_once`. Returns `None` if `delim` does not occur.
+    pub fn split_once(&self, delim: char) -> Option<(RopeSlice<'_>, RopeSlice<'_>)> {
+        let found = self.full_slice().char_indices().find(|&(_, c)| c == delim);
+        found.map(|(i, _)| (self.slice(0..i), self.slice(i + delim.len_utf8()..self.len)))
+    }
+
+    // Split the rope around the last occurrence of `delim`, like
+    // `str::rsplit_once`. Returns `None` if `delim` does not occur.
+    pub fn rsplit_once(&self, delim: char) -> Option<(RopeSlice<'_>, RopeSlice<'_>)> {
+        let found = self.chars_rev().find(|&(c, _)| c == delim);
+        found.map(|(_, i)| (self.slice(0..i), self.slice(i + delim.len_utf8()..self.len)))
+    }
+
+    // The rest of the rope after `prefix`, or `None` if the rope does not
+    // start with `prefix`.
+    pub fn strip_prefix(&self, prefix: &str) -> Option<RopeSlice<'_>> {
+        if prefix.len() > self.len {
+            return None;
+        }
+
+        let matches = self.full_slice().bytes().zip(prefix.bytes()).all(|(a, b)| a == b);
+        if matches {
+            Some(self.slice(prefix.len()..self.len))
+        } else {
+            None
+        }
+    }
+
+    // The rest of the rope before `suffix`, or `None` if the rope does not
+    // end with `suffix`.
+    pub fn strip_suffix(&self, suffix: &str) -> Option<RopeSlice<'_>> {
+        if suffix.len() > self.len {
+            return None;
+        }
+
+        let matches = self.full_slice().bytes().rev().zip(suffix.bytes().rev()).all(|(a, b)| a == b);
+        if matches {
+            Some(self.slice(0..self.len - suffix.len()))
+        } else {
+            None
+        }
+    }
+
+    // The char whose bytes include `byte`, and the offset of the start of that
+    // char. Returns `None` if `byte` is not within the rope.
+    pub fn char_at_or_before(&self, byte: usize) -> Option<(char, usize)> {
+        if byte >= self.len {
+            return None;
+        }
+
+        let start = self.clamp_to_boundary(byte);
+        let slice = self.slice(start..self.len);
+        let result = slice.char_indices().next().map(|(_, c)| (c, start));
+        result
+    }
+
+    // The length in bytes of the longest common prefix of the rope and `s`.
+    // The prefix always ends on a char boundary.
+    pub fn common_prefix_with(&self, s: &str) -> usize {
+        let mut common = 0;
+        for (i, (a, b)) in self.full_slice().bytes().zip(s.bytes()).enumerate() {
+            if a != b {
+                break;
             }
+            if s.is_char_boundary(i + 1) {
+                common = i + 1;
+            }
+        }
+        common
+    }
+
+    // The number of bytes in the rope for which `f` returns true.
+    pub fn count_bytes<F>(&self, f: F) -> usize
+        where F: Fn(u8) -> bool
+    {
+        self.count_bytes_to(self.len, f)
+    }
+
+    // Whether `byte` is the start of a char or the end of the rope, like
+    // `str::is_char_boundary`. Offsets past the end are not boundaries.
+    pub fn is_char_boundary(&self, byte: usize) -> bool {
+        if byte >= self.len {
+            return byte == self.len;
+        }
+        self.slice(byte..byte + 1).bytes().next().is_some_and(is_char_start)
+    }
+
+    // The greatest char boundary which is less than or equal to `byte`.
+    // Offsets past the end of the rope are clamped to its length.
+    pub fn clamp_to_boundary(&self, byte: usize) -> usize {
+        if byte >= self.len {
+            return self.len;
+        }
+
+        // A char is at most four bytes long.
+        let slice = self.slice(byte.saturating_sub(3)..byte + 1);
+        let back = slice.bytes().rev().position(is_char_start).expect("non-utf8 char in rope");
+        byte - back
+    }
+
+    // Move the byte offset `from` forwards (if `n_chars` is positive) or
+    // backwards (if negative) by `n_chars` chars. The result is clamped to the
+    // start and end of the rope.
+    pub fn advance(&self, from: usize, n_chars: isize) -> usize {
+        assert!(from <= self.len, "byte {} out of bounds of rope (len {})", from, self.len);
+
+        if n_chars >= 0 {
+            let n_chars = n_chars as usize;
+            let slice = self.slice(from..self.len);
+            let mut seen = 0;
+            for (i, b) in slice.bytes().enumerate() {
+                if i > 0 && is_char_start(b) {
+                    seen += 1;
+                    if seen == n_chars {
+                        return from + i;
+                    }
+                }
+            }
+            if n_chars == 0 {
+                from
+            } else {
+                self.len
+            }
+        } else {
+            let n_chars = (-n_chars) as usize;
+            let slice = self.slice(0..from);
+            let mut seen = 0;
+            for (i, b) in slice.bytes().rev().enumerate() {
+                if is_char_start(b) {
+                    seen += 1;
+                    if seen == n_chars {
+                        return from - i - 1;
+                    }
+                }
+            }
+            0
+        }
+    }
+
+    // The byte offset of the start of the char at index `char_idx`. `char_idx`
+    // may be the number of chars in the rope, in which case the length of the
+    // rope is returned.
+    //
+    // O(depth of the tree), using the char counts cached in each inner node,
+    // plus a scan of one leaf.
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        let total = self.len_chars();
+        assert!(char_idx <= total, "char {} out of bounds of rope ({} chars)", char_idx, total);
+        if char_idx == total {
+            return self.len;
+        }
+
+        let mut char_idx = char_idx;
+        let mut offset = 0;
+        let mut node = &self.root;
+        loop {
+            match *node {
+                Node::InnerNode(ref i) => {
+                    if char_idx < i.char_weight {
+                        node = i.left.as_deref().unwrap();
+                    } else {
+                        char_idx -= i.char_weight;
+                        offset += i.weight;
+                        node = i.right.as_deref().unwrap();
+                    }
+                }
+                Node::LeafNode(ref l) => {
+                    let (i, _) = l.bytes().iter()
+                                          .enumerate()
+                                          .filter(|&(_, &b)| is_char_start(b))
+                                          .nth(char_idx)
+                                          .unwrap();
+                    return offset + i;
+                }
+            }
+        }
+    }
+
+    // Check that `byte` is a valid offset at which to edit the rope.
+    fn check_offset(&self, byte: usize) -> Result<(), RopeError> {
+        if byte > self.len {
+            return Err(RopeError::OutOfBounds { offset: byte, len: self.len });
+        }
+        if !self.is_char_boundary(byte) {
+            return Err(RopeError::NotCharBoundary(byte));
+        }
+        Ok(())
+    }
+
+    // The number of chars which start before byte offset `byte`.
+    //
+    // O(depth of the tree), using the char counts cached in each inner node,
+    // plus a scan of one leaf.
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        assert!(byte <= self.len, "byte {} out of bounds of rope (len {})", byte, self.len);
+
+        let mut byte = byte;
+        let mut chars = 0;
+        let mut node = &self.root;
+        loop {
+            match *node {
+                Node::InnerNode(ref i) => {
+                    if byte < i.weight {
+                        node = i.left.as_deref().unwrap();
+                    } else {
+                        byte -= i.weight;
+                        chars += i.char_weight;
+                        match i.right {
+                            Some(ref r) => node = r,
+                            None => return chars,
+                        }
+                    }
+                }
+                Node::LeafNode(ref l) => return chars + char_count(&l.bytes()[..byte]),
+            }
+        }
+    }
+
+    // As `byte_to_char`, but returns `None` if `byte` is out of bounds or not
+    // a char boundary, rather than panicking or counting the chars which
+    // start before it.
+    pub fn byte_to_char_checked(&self, byte: usize) -> Option<usize> {
+        if self.check_offset(byte).is_err() {
+            return None;
+        }
+        Some(self.byte_to_char(byte))
+    }
+
+    // The smallest range of `self` which differs from `other`, or `None` if the
+    // two ropes are equal. The range is `common_prefix..len - common_suffix`
+    // (so the corresponding range in `other` is `common_prefix..other.len() -
+    // common_suffix`); it is empty if `other` only adds text to `self`. The
+    // ends of the range are always on char boundaries.
+    pub fn diff_range(&self, other: &Rope) -> Option<Range<usize>> {
+        let self_slice = self.full_slice();
+        let other_slice = other.full_slice();
+
+        let mut prefix = 0;
+        let mut last_start = 0;
+        for (a, b) in self_slice.bytes().zip(other_slice.bytes()) {
+            if is_char_start(a) {
+                last_start = prefix;
+            }
+            if a != b {
+                break;
+            }
+            prefix += 1;
+        }
+        if prefix < self.len && prefix < other.len {
+            // Don't split a char which differs part way through.
+            prefix = last_start;
+        }
+
+        if prefix == self.len && prefix == other.len {
+            return None;
+        }
+
+        let max_suffix = ::std::cmp::min(self.len, other.len) - prefix;
+        let mut suffix = 0;
+        for (i, (a, b)) in self_slice.bytes().rev().zip(other_slice.bytes().rev()).enumerate() {
+            if i == max_suffix || a != b {
+                break;
+            }
+            if is_char_start(a) {
+                suffix = i + 1;
+            }
+        }
+
+        Some(prefix..self.len - suffix)
+    }
+
+    // The offset of the first `needle` at or after `start`.
+    fn find_byte_from(&self, start: usize, needle: u8) -> Option<usize> {
+        let slice = self.slice(start..self.len);
+        let mut offset = start;
+        for chunk in slice.chunks() {
+            if let Some(i) = chunk.iter().position(|&b| b == needle) {
+                return Some(offset + i);
+            }
+            offset += chunk.len();
+        }
+        None
+    }
+
+    // Count the bytes before offset `end` for which `f` returns true.
+    fn count_bytes_to<F>(&self, end: usize, f: F) -> usize
+        where F: Fn(u8) -> bool
+    {
+        let mut count = 0;
+        let mut offset = 0;
+        let slice = self.full_slice();
+        for chunk in slice.chunks() {
+            if offset >= end {
+                break;
+            }
+            let chunk = &chunk[..::std::cmp::min(chunk.len(), end - offset)];
+            count += chunk.iter().filter(|&&b| f(b)).count();
+            offset += chunk.len();
+        }
+        count
+    }
+}
+
+impl fmt::Display for RopeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RopeError::OutOfBounds { offset, len } => {
+                write!(fmt, "offset {} out of bounds of rope (len {})", offset, len)
+            }
+            RopeError::NotCharBoundary(offset) => {
+                write!(fmt, "offset {} is not a char boundary", offset)
+            }
+            RopeError::InvalidPosition { line, col } => {
+                write!(fmt, "line {} column {} is not in the rope", line, col)
+            }
+            RopeError::Overlap(ref a, ref b) => {
+                write!(fmt, "ranges {:?} and {:?} overlap", a, b)
+            }
+            RopeError::ReversedRange(ref range) => {
+                write!(fmt, "range {:?} is reversed", range)
+            }
+        }
+    }
+}
+
+impl Error for RopeError {}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PatchError::OutOfBounds(hunk) => write!(fmt, "hunk {} is not within the rope", hunk),
+            PatchError::Overlap(a, b) => write!(fmt, "hunks {} and {} overlap", a, b),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+impl LineIndex {
+    // The number of lines, as `Rope::len_lines`.
+    pub fn len_lines(&self) -> usize {
+        self.starts.len()
+    }
+
+    // The line containing the byte at offset `byte`, as `Rope::byte_to_line`.
+    pub fn line_at(&self, byte: usize) -> usize {
+        assert!(byte <= self.len, "byte {} out of bounds of rope (len {})", byte, self.len);
+        match self.starts.binary_search(&byte) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        }
+    }
+
+    // The byte offset of the start of `line`, as `Rope::line_to_byte`. `line`
+    // may be one past the last line, in which case the length of the rope is
+    // returned.
+    pub fn offset_of_line(&self, line: usize) -> usize {
+        assert!(line <= self.starts.len(),
+                "line {} out of bounds of rope ({} lines)", line, self.starts.len());
+        self.starts.get(line).cloned().unwrap_or(self.len)
+    }
+}
+
+impl<'rope> RopeSlice<'rope> {
+    fn empty<'r>() -> RopeSlice<'r> {
+        RopeSlice {
+            nodes: vec![],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    // The bytes of the `i`th node which fall within the slice.
+    fn chunk(&self, i: usize) -> &'rope [u8] {
+        let node = self.nodes[i];
+        let mut start = 0;
+        let mut len = node.len;
+        if i == 0 {
+            start = self.start;
+            len -= self.start;
+        }
+        if i == self.nodes.len() - 1 {
+            len = self.len;
+        }
+        unsafe {
+            ::std::slice::from_raw_parts((node.text as usize + start) as *const u8, len)
+        }
+    }
+
+    // The bytes of the slice, one chunk per node.
+    fn chunks<'a>(&'a self) -> impl DoubleEndedIterator<Item = &'rope [u8]> + 'a {
+        (0..self.nodes.len()).map(move |i| self.chunk(i))
+    }
+
+    // The bytes of the slice.
+    fn bytes<'a>(&'a self) -> impl DoubleEndedIterator<Item = u8> + 'a {
+        self.chunks().flat_map(|c| c.iter().cloned())
+    }
+
+    // The offset (relative to the slice) of the first occurrence of `needle`.
+    fn find(&self, needle: &[u8]) -> Option<usize> {
+        if needle.is_empty() {
+            return Some(0);
+        }
+
+        let mut window = VecDeque::with_capacity(needle.len());
+        for (i, b) in self.bytes().enumerate() {
+            if window.len() == needle.len() {
+                window.pop_front();
+            }
+            window.push_back(b);
+            if window.len() == needle.len() && window.iter().eq(needle.iter()) {
+                return Some(i + 1 - needle.len());
+            }
+        }
+        None
+    }
+
+    // The offset (relative to the slice) of the last occurrence of `needle`.
+    fn rfind(&self, needle: &[u8]) -> Option<usize> {
+        let len: usize = self.chunks().map(|c| c.len()).sum();
+        if needle.is_empty() {
+            return Some(len);
+        }
+
+        let mut window = VecDeque::with_capacity(needle.len());
+        for (i, b) in self.bytes().rev().enumerate() {
+            if window.len() == needle.len() {
+                window.pop_back();
+            }
+            window.push_front(b);
+            if window.len() == needle.len() && window.iter().eq(needle.iter()) {
+                return Some(len - i - 1);
+            }
+        }
+        None
+    }
+
+    // The chars of the slice, with their offsets from the start of the slice.
+    fn char_indices<'a>(&'a self) -> impl Iterator<Item = (usize, char)> + 'a {
+        let mut bytes = self.bytes();
+        let mut offset = 0;
+        ::std::iter::from_fn(move || {
+            let first = bytes.next()?;
+            let c = decode_char(first, &mut bytes);
+            let start = offset;
+            offset += c.len_utf8();
+            Some((start, c))
+        })
+    }
+
+    pub fn iter_chars(self) -> RopeChars<'rope> {
+        let start = self.start;
+        RopeChars {
+            data: self,
+            cur_node: 0,
+            cur_byte: start,
+            abs_byte: start,
+        }
+    }
+}
+
+impl<'rope> Iterator for RopeChars<'rope> {
+    type Item = (char, usize);
+    fn next(&mut self) -> Option<(char, usize)> {
+        if self.cur_node >= self.data.nodes.len() {
+            return None;
+        }
+
+        let byte = self.abs_byte;
+        let node = self.data.nodes[self.cur_node];
+
+        if self.cur_node == self.data.nodes.len() - 1 {
+
+            if self.cur_node == 0 && self.cur_byte > self.data.len + self.data.start {
+                // this is the first & last node
+                // we are at the end of the target slice
+                return None
+            }
+
+            if self.cur_byte + 1 > node.len - self.data.start {
+                // this is the last node
+                // we are at the end of the target slice
+                return None
+            }
+        }
+
+        if self.cur_byte >= node.len {
+            self.cur_byte = 0;
+            self.cur_node += 1;
+            return self.next();
+        }
+
+        let result = self.read_char();
+        return Some((result, byte));
+    }
+}
+
+impl<'rope> RopeCharsRev<'rope> {
+    // `end` is the offset in the rope of the end of `data`.
+    fn new(data: RopeSlice<'rope>, end: usize) -> RopeCharsRev<'rope> {
+        let cur_node = data.nodes.len();
+        let cur_byte = if cur_node > 0 {
+            data.chunk(cur_node - 1).len()
+        } else {
+            0
+        };
+        RopeCharsRev {
+            data,
+            cur_node,
+            cur_byte,
+            abs_byte: end,
+        }
+    }
+
+    fn read_byte_back(&mut self) -> Option<u8> {
+        while self.cur_byte == 0 {
+            if self.cur_node <= 1 {
+                return None;
+            }
+            self.cur_node -= 1;
+            self.cur_byte = self.data.chunk(self.cur_node - 1).len();
+        }
+
+        self.cur_byte -= 1;
+        self.abs_byte -= 1;
+        Some(self.data.chunk(self.cur_node - 1)[self.cur_byte])
+    }
+}
+
+impl<'rope> Iterator for RopeCharsRev<'rope> {
+    type Item = (char, usize);
+    fn next(&mut self) -> Option<(char, usize)> {
+        // Read backwards until we find the first byte of a char.
+        let mut buf = [0; 4];
+        let mut start = 4;
+        loop {
+            if start == 0 {
+                panic!("non-utf8 char in rope");
+            }
+            start -= 1;
+            buf[start] = self.read_byte_back()?;
+            if is_char_start(buf[start]) {
+                break;
+            }
+        }
+
+        let c = decode_char(buf[start], &mut buf[start + 1..].iter().cloned());
+        Some((c, self.abs_byte))
+    }
+}
+
+impl<'rope> Iterator for RopeLines<'rope> {
+    type Item = RopeSlice<'rope>;
+    fn next(&mut self) -> Option<RopeSlice<'rope>> {
+        let start = self.next?;
+        let end = match self.rope.find_byte_from(start, b'\n') {
+            Some(end) => {
+                self.next = Some(end + 1);
+                end
+            }
+            None => {
+                self.next = None;
+                self.rope.len
+            }
+        };
+        Some(self.rope.slice(start..end))
+    }
+}
+
+impl<'rope> RopeChars<'rope> {
+    // The current position of the iterator, which can be restored with
+    // `seek_to`.
+    pub fn position(&self) -> RopeCharPos {
+        RopeCharPos {
+            node: self.cur_node,
+            byte: self.cur_byte,
+            abs_byte: self.abs_byte,
+        }
+    }
+
+    // Return to a position previously saved by calling `position` on this
+    // iterator (or another iterator over the same slice).
+    pub fn seek_to(&mut self, pos: RopeCharPos) {
+        assert!(pos.node <= self.data.nodes.len(), "position {:?} is not in this iterator", pos);
+        self.cur_node = pos.node;
+        self.cur_byte = pos.byte;
+        self.abs_byte = pos.abs_byte;
+    }
+
+    fn read_char(&mut self) -> char {
+        let first_byte = self.read_byte();
+        let width = utf8_char_width(first_byte);
+        if width == 1 {
+            return first_byte as char
+        }
+        if width == 0 {
+            panic!("non-utf8 char in rope");
+        }
+        let mut buf = [first_byte, 0, 0, 0];
+        {
+            let mut start = 1;
+            while start < width {
+                buf[start] = self.read_byte();
+                start += 1;
+            }
+        }
+        match ::std::str::from_utf8(&buf[..width]).ok() {
+            Some(s) => s.chars().nth(0).expect("FATAL: we checked presence of this before"),
+            None => panic!("bad chars in rope")
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let node = self.data.nodes[self.cur_node];
+        let addr = node.text as usize + self.cur_byte;
+        self.cur_byte += 1;
+        self.abs_byte += 1;
+        let addr = addr as *const u8;
+        unsafe {
+            *addr
+        }
+    }
+}
+
+impl Drop for Rope {
+    // Dropping the tree recursively can overflow the stack when it is deep,
+    // so take it apart one node at a time.
+    fn drop(&mut self) {
+        let mut stack = vec![::std::mem::replace(&mut self.root, Node::empty_inner())];
+        while let Some(mut node) = stack.pop() {
+            if let Node::InnerNode(ref mut i) = node {
+                stack.extend(i.left.take().map(|n| *n));
+                stack.extend(i.right.take().map(|n| *n));
+            }
+        }
+    }
+}
+
+impl ::std::str::FromStr for Rope {
+    type Err = ();
+    fn from_str(text: &str) -> Result<Rope, ()> {
+        let mut result = Rope::new();
+        result.insert_copy(0, text);
+        Ok(result)
+    }
+}
+
+impl<'a> fmt::Display for RopeSlice<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.nodes.len() == 0 {
+            return Ok(());
+        }
+
+        let last_idx = self.nodes.len() - 1;
+        for (i, n) in self.nodes.iter().enumerate() {
+            let mut ptr = n.text;
+            let mut len = n.len;
+            if i == 0 {
+                ptr = (ptr as usize + self.start) as *const u8;
+                len -= self.start;
+            }
+            if i == last_idx {
+                len = self.len;
+            }
+            unsafe {
+                try!(write!(fmt,
+                            "{}",
+                            ::std::str::from_utf8(::std::slice::from_raw_parts(ptr, len)).unwrap()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for RopeSlice<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let last_idx = self.nodes.len() - 1;
+        for (i, n) in self.nodes.iter().enumerate() {
+            let mut ptr = n.text;
+            let mut len = n.len;
+            if i == 0 {
+                ptr = (ptr as usize + self.start) as *const u8;
+                len -= self.start;
+            } else {
+                try!(write!(fmt, "|"));
+            }
+            if i == last_idx {
+                len = self.len;
+            }
+            unsafe {
+                try!(write!(fmt,
+                            "\"{}\"",
+                            ::std::str::from_utf8(::std::slice::from_raw_parts(ptr, len)).unwrap()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // Display the full slice rather than the root node, since the slice is
+        // found without recursion.
+        write!(fmt, "{}", self.full_slice())
+    }
+}
+
+impl fmt::Debug for Rope {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{:?}", self.root)
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Node::InnerNode(Inode { ref left, ref right, .. }) => {
+                if let Some(ref left) = *left {
+                    write!(fmt, "{}", left)
+                } else {
+                    Ok(())
+                }.and_then(|_| if let Some(ref right) = *right {
+                    write!(fmt, "{}", right)
+                } else {
+                    Ok(())
+                })
+            }
+            Node::LeafNode(Lnode{ ref text, len, .. }) => {
+                unsafe {
+                    write!(fmt,
+                           "{}",
+                           ::std::str::from_utf8(::std::slice::from_raw_parts(*text, len)).unwrap())
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Node::InnerNode(Inode { ref left, ref right, weight, .. }) => {
+                try!(write!(fmt, "("));
+                if let Some(ref left) = *left {
+                    try!(write!(fmt, "left: {:?}", &**left));
+                } else {
+                    try!(write!(fmt, "left: ()"));
+                }
+                try!(write!(fmt, ", "));
+                if let Some(ref right) = *right {
+                    try!(write!(fmt, "right: {:?}", &**right));
+                } else {
+                    try!(write!(fmt, "right: ()"));
+                }
+                write!(fmt, "; {})", weight)
+            }
+            Node::LeafNode(Lnode{ ref text, len, .. }) => {
+                unsafe {
+                    write!(fmt,
+                           "(\"{}\"; {})",
+                           ::std::str::from_utf8(::std::slice::from_raw_parts(*text, len)).unwrap(),
+                           len)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+enum Node {
+    InnerNode(Inode),
+    LeafNode(Lnode),
+}
+
+#[derive(Clone, Eq, PartialEq)]
+struct Inode {
+    weight: usize,
+    // The number of chars in the left subtree, maintained alongside `weight`.
+    char_weight: usize,
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
+}
+
+#[derive(Clone, Eq, PartialEq)]
+struct Lnode {
+    text: *const u8,
+    len: usize,
+    // The number of char starts in `text`. A leaf split inside a char has
+    // fewer chars than its bytes suggest.
+    chars: usize,
+}
+
+impl Node {
+    fn empty_inner() -> Node {
+        Node::InnerNode(Inode {
+            left: None,
+            right: None,
+            weight: 0,
+            char_weight: 0,
+        })
+    }
+
+    fn new_inner(left: Option<Box<Node>>,
+                 right: Option<Box<Node>>,
+                 weight: usize)
+    -> Node {
+        Node::InnerNode(Inode {
+            char_weight: left.as_ref().map_or(0, |l| l.char_len()),
+            left: left,
+            right: right,
+            weight: weight,
+        })
+    }
+
+    fn new_leaf(text: *const u8, len: usize) -> Node {
+        Node::LeafNode(Lnode::new(text, len))
+    }
+
+    // Build a balanced tree over `leaves`, which must not be empty.
+    fn from_leaves(leaves: &[Lnode]) -> Node {
+        if leaves.len() == 1 {
+            return Node::LeafNode(leaves[0].clone());
+        }
+
+        let mid = leaves.len() / 2;
+        let left = Node::from_leaves(&leaves[..mid]);
+        let right = Node::from_leaves(&leaves[mid..]);
+        let weight = left.len();
+        Node::new_inner(Some(Box::new(left)), Some(Box::new(right)), weight)
+    }
+
+    // The leaves of this subtree, in order.
+    fn leaves(&self) -> Vec<&Lnode> {
+        let mut slice = RopeSlice::empty();
+        let len = self.len();
+        if len > 0 {
+            self.find_slice(0, len, &mut slice);
+        }
+        slice.nodes
+    }
+
+    // Iterative, since the right spine of an unbalanced rope can be very
+    // long.
+    fn len(&self) -> usize {
+        let mut result = 0;
+        let mut node = self;
+        loop {
+            match *node {
+                Node::InnerNode(Inode { weight, ref right, .. }) => {
+                    result += weight;
+                    match *right {
+                        Some(ref r) => node = r,
+                        None => return result,
+                    }
+                }
+                Node::LeafNode(Lnode { len, .. }) => return result + len,
+            }
+        }
+    }
+
+    // As `len`, but counting chars.
+    fn char_len(&self) -> usize {
+        let mut result = 0;
+        let mut node = self;
+        loop {
+            match *node {
+                Node::InnerNode(Inode { char_weight, ref right, .. }) => {
+                    result += char_weight;
+                    match *right {
+                        Some(ref r) => node = r,
+                        None => return result,
+                    }
+                }
+                Node::LeafNode(Lnode { chars, .. }) => return result + chars,
+            }
+        }
+    }
+
+    // Most of these methods are just doing dynamic dispatch, TODO use a macro
+
+    // precond: start < end
+    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
+        match *self {
+            Node::InnerNode(ref mut i) => i.remove(start, end),
+            Node::LeafNode(ref mut l) => l.remove(start, end),
+        }
+    }
+
+    // Iterative, since the tree may be very deep. Each inner node on the way
+    // down grows by the length of `node`, which then either fills an empty
+    // child or replaces the leaf it is inserted into.
+    fn insert(&mut self, node: Box<Node>, start: usize) -> NodeAction {
+        let len = node.len();
+        let chars = node.char_len();
+        let mut cur = self;
+        let mut start = start;
+        loop {
+            if let Node::LeafNode(ref mut l) = *cur {
+                match l.insert(node, start) {
+                    NodeAction::Change(n, ..) => *cur = *n,
+                    _ => panic!("Unexpected action"),
+                }
+                return NodeAction::Adjust(len as isize, chars as isize);
+            }
+
+            let i = match *cur {
+                Node::InnerNode(ref mut i) => i,
+                Node::LeafNode(_) => unreachable!(),
+            };
+            if start <= i.weight {
+                i.weight += len;
+                i.char_weight += chars;
+                if i.left.is_none() {
+                    assert!(i.weight == len);
+                    i.left = Some(node);
+                    return NodeAction::Adjust(len as isize, chars as isize);
+                }
+                cur = i.left.as_mut().unwrap();
+            } else {
+                if i.right.is_none() {
+                    i.right = Some(node);
+                    return NodeAction::Adjust(len as isize, chars as isize);
+                }
+                start -= i.weight;
+                cur = i.right.as_mut().unwrap();
+            }
+        }
+    }
+
+    // Iterative, using an explicit stack, since the tree may be very deep.
+    fn find_slice<'a>(&'a self, start: usize, end: usize, slice: &mut RopeSlice<'a>) {
+        let mut stack = vec![(self, start, end)];
+        while let Some((node, start, end)) = stack.pop() {
+            match *node {
+                Node::InnerNode(ref i) => {
+                    debug!("Inode::find_slice: {}, {}, {}", start, end, i.weight);
+                    // Push the right child first, so that the left is visited
+                    // first.
+                    if end > i.weight {
+                        let start = start.saturating_sub(i.weight);
+                        stack.push((i.right.as_deref().unwrap(), start, end - i.weight));
+                    }
+                    if start < i.weight {
+                        stack.push((i.left.as_deref().unwrap(), start, end));
+                    }
+                }
+                Node::LeafNode(ref l) => l.find_slice(start, end, slice),
+            }
+        }
+    }
+
+    fn replace(&mut self, start: usize, new_str: &str) -> isize {
+        self.replace_bytes(start, new_str.as_bytes())
+    }
+
+    // Overwrite the text at `start` with `new_bytes`, which may span several
+    // leaves. A leaf boundary may fall inside a char of `new_bytes`. Returns
+    // the change in the number of chars.
+    fn replace_bytes(&mut self, start: usize, new_bytes: &[u8]) -> isize {
+        match *self {
+            Node::InnerNode(ref mut i) => i.replace(start, new_bytes),
+            Node::LeafNode(ref mut l) => l.replace(start, new_bytes),
+        }
+    }
+
+    // Call `f` on each leaf, in order. `f` must not change which bytes start
+    // chars, since the cached char counts are not updated.
+    fn for_each_leaf_mut<F>(&mut self, f: &mut F)
+        where F: FnMut(&mut Lnode)
+    {
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            match *node {
+                Node::InnerNode(Inode { ref mut left, ref mut right, .. }) => {
+                    // Push the right child first, so that the left is visited
+                    // first.
+                    if let Some(ref mut right) = *right {
+                        stack.push(right);
+                    }
+                    if let Some(ref mut left) = *left {
+                        stack.push(left);
+                    }
+                }
+                Node::LeafNode(ref mut l) => f(l),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum NodeAction {
+    None,
+    Remove,
+    // Args are the change in length, in bytes and in chars, of the adjusted
+    // node (new length - old length).
+    Adjust(isize, isize),
+    // Args are the new node and the change in length, in bytes and in chars.
+    Change(Box<Node>, isize, isize)
+}
+
+impl Inode {
+    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
+        debug!("Inode::remove: {}, {}, {}", start, end, self.weight);
+
+        let left_action = if start <= self.weight {
+            if let Some(ref mut left) = self.left {
+                left.remove(start, end)
+            } else {
+                panic!();
+            }
+        } else {
+            NodeAction::None
+        };
+
+        let right_action = if end > self.weight {
+            if let Some(ref mut right) = self.right {
+                let start = if start < self.weight {
+                    0
+                } else {
+                    start - self.weight
+                };
+                right.remove(start, end - self.weight)
+            } else {
+                panic!();
+            }
+        } else {
+            NodeAction::None
+        };
+
+
+        if left_action == NodeAction::Remove && right_action == NodeAction::Remove ||
+           left_action == NodeAction::Remove && self.right.is_none() ||
+           right_action == NodeAction::Remove && self.left.is_none() {
+            return NodeAction::Remove;
+        }
+
+        // If one side is removed, we are replaced by the other side, taking
+        // into account any changes to that side.
+        if left_action == NodeAction::Remove {
+            let (right, adj, char_adj) = match right_action {
+                NodeAction::Change(n, adj, char_adj) => (n, adj, char_adj),
+                NodeAction::Adjust(adj, char_adj) => (self.right.clone().unwrap(), adj, char_adj),
+                _ => (self.right.clone().unwrap(), 0, 0),
+            };
+            return NodeAction::Change(right,
+                                      adj - self.weight as isize,
+                                      char_adj - self.char_weight as isize);
+        }
+        if right_action == NodeAction::Remove {
+            let right = self.right.as_ref().unwrap();
+            let (right_len, right_chars) = (right.len() as isize, right.char_len() as isize);
+            let (left, adj, char_adj) = match left_action {
+                NodeAction::Change(n, adj, char_adj) => (n, adj, char_adj),
+                NodeAction::Adjust(adj, char_adj) => (self.left.clone().unwrap(), adj, char_adj),
+                _ => (self.left.clone().unwrap(), 0, 0),
+            };
+            return NodeAction::Change(left, adj - right_len, char_adj - right_chars);
+        }
+
+        let mut total_adj = 0;
+        let mut total_char_adj = 0;
+        if let NodeAction::Change(ref n, adj, char_adj) = left_action {
+            self.left = Some(n.clone());
+            self.weight = (self.weight as isize + adj) as usize;
+            self.char_weight = (self.char_weight as isize + char_adj) as usize;
+            total_adj += adj;
+            total_char_adj += char_adj;
+        }
+        if let NodeAction::Change(ref n, adj, char_adj) = right_action {
+            self.right = Some(n.clone());
+            total_adj += adj;
+            total_char_adj += char_adj;
+        }
+
+        if let NodeAction::Adjust(adj, char_adj) = left_action {
+            self.weight = (self.weight as isize + adj) as usize;
+            self.char_weight = (self.char_weight as isize + char_adj) as usize;
+            total_adj += adj;
+            total_char_adj += char_adj;
+        }
+        if let NodeAction::Adjust(adj, char_adj) = right_action {
+            total_adj += adj;
+            total_char_adj += char_adj;
+        }
+
+        return NodeAction::Adjust(total_adj, total_char_adj);
+    }
+
+    fn replace(&mut self, start: usize, new_bytes: &[u8]) -> isize {
+        debug!("Inode::replace: {}, {:?}, {}", start, new_bytes, self.weight);
+        let end = start + new_bytes.len();
+        let mut char_adj = 0;
+        if start < self.weight {
+            if let Some(ref mut left) = self.left {
+                let left_bytes = &new_bytes[..::std::cmp::min(self.weight - start, new_bytes.len())];
+                let adj = left.replace_bytes(start, left_bytes);
+                self.char_weight = (self.char_weight as isize + adj) as usize;
+                char_adj += adj;
+            } else {
+                panic!();
+            }
+        }
+        if end > self.weight {
+            let (start, offset) = if start < self.weight {
+                (0, self.weight - start)
+            } else {
+                (start - self.weight, 0)
+            };
+            if let Some(ref mut right) = self.right {
+                char_adj += right.replace_bytes(start, &new_bytes[offset..]);
+            } else {
+                panic!();
+            }
+        }
+        char_adj
+    }
+}
+
+impl Lnode {
+    fn new(text: *const u8, len: usize) -> Lnode {
+        let mut result = Lnode {
+            text: text,
+            len: len,
+            chars: 0,
+        };
+        result.chars = char_count(result.bytes());
+        result
+    }
+
+    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
+        debug!("Lnode::remove: {}, {}, {}", start, end, self.len);
+        assert!(start <= self.len);
+
+        if start == 0 && end >= self.len {
+            // The removal span includes us, remove ourselves.
+            return NodeAction::Remove;
+        }
+
+        let old_len = self.len;
+        if start == 0 {
+            // Truncate the left of the node.
+            let removed_chars = char_count(&self.bytes()[..end]);
+            self.chars -= removed_chars;
+            self.text = (self.text as usize + end) as *const u8;
+            self.len = old_len - end;
+            let delta = self.len as isize - old_len as isize;
+            return NodeAction::Adjust(delta, -(removed_chars as isize));
+        }
+
+        if end >= self.len {
+            // Truncate the right of the node.
+            let removed_chars = char_count(&self.bytes()[start..]);
+            self.chars -= removed_chars;
+            self.len = start;
+            return NodeAction::Adjust(self.len as isize - old_len as isize,
+                                      -(removed_chars as isize));
+        }
+
+        let delta = -((end - start) as isize);
+        let char_delta = -(char_count(&self.bytes()[start..end]) as isize);
+        // Split the node (span to remove is in the middle of the node).
+        let new_node = Node::new_inner(
+            Some(Box::new(Node::new_leaf(self.text, start))),
+            Some(Box::new(Node::new_leaf((self.text as usize + end) as *const u8,
+                                    old_len - end))),
+            start);
+        return NodeAction::Change(Box::new(new_node), delta, char_delta);
+    }
+
+    fn insert(&mut self, node: Box<Node>, start: usize) -> NodeAction {
+        let len = node.len();
+        let chars = node.char_len() as isize;
+        if start == 0 {
+            // Insert at the start of the node
+            let new_node = Box::new(Node::new_inner(Some(node),
+                                                    Some(Box::new(Node::LeafNode(self.clone()))),
+                                                    len));
+            return NodeAction::Change(new_node, len as isize, chars)
+        }
+
+        if start == self.len {
+            // Insert at the end of the node
+            let new_node = Box::new(Node::new_inner(Some(Box::new(Node::LeafNode(self.clone()))),
+                                                    Some(node),
+                                                    self.len));
+            return NodeAction::Change(new_node, len as isize, chars)
+        }
+
+        // Insert into the middle of the node
+        let left = Some(Box::new(Node::new_leaf(self.text, start)));
+        let new_left = Box::new(Node::new_inner(left, Some(node), start));
+        let right = Some(Box::new(Node::new_leaf((self.text as usize + (start)) as *const u8,
+                                                  self.len - start)));
+        let new_node = Box::new(Node::new_inner(Some(new_left), right, start + len));
+
+        return NodeAction::Change(new_node, len as isize, chars)
+    }
+
+    fn find_slice<'a>(&'a self, start: usize, end: usize, slice: &mut RopeSlice<'a>) {
+        debug!("Lnode::find_slice: {}, {}, {}", start, end, self.len);
+        debug_assert!(start < self.len, "Shouldn't have called this fn, we're out of bounds");
+
+        slice.nodes.push(self);
+        let mut len = ::std::cmp::min(end, self.len);
+        if start > 0 {
+            slice.start = start;
+            len -= start;
+        }
+        slice.len = len;
+    }
+
+    // Returns the change in the number of chars.
+    fn replace(&mut self, start: usize, new_bytes: &[u8]) -> isize {
+        debug!("Lnode::replace: {}, {:?}, {}", start, new_bytes, self.len);
+        debug_assert!(start + new_bytes.len() <= self.len);
+
+        let range = start..start + new_bytes.len();
+        let old_chars = char_count(&self.bytes()[range.clone()]);
+        let new_chars = char_count(new_bytes);
+        self.chars = self.chars - old_chars + new_chars;
+        self.bytes_mut()[range].copy_from_slice(new_bytes);
+        new_chars as isize - old_chars as isize
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe {
+            ::std::slice::from_raw_parts(self.text, self.len)
+        }
+    }
+
+    // The text of this leaf, for overwriting in place.
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            ::std::slice::from_raw_parts_mut(self.text as *mut u8, self.len)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let r = Rope::new();
+        assert!(r.len() == 0);
+        assert!(r.to_string() == "");
+
+        let r = Rope::from_string("Hello world!".to_string());
+        assert!(r.len() == 12);
+        assert!(r.to_string() == "Hello world!");
+    }
+
+    #[test]
+    fn test_from_string() {
+        let r: Rope = "Hello world!".parse().unwrap();
+        assert!(r.to_string() == "Hello world!");
+    }
+
+    #[test]
+    fn test_slice_empty_rope() {
+        let r: Rope = Rope::new();
+        let _ = r.full_slice();
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.remove(0, 10);
+        assert!(r.to_string() == "d!");
+
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.remove(4, 12);
+        assert!(r.to_string() == "Hell");
+
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.remove(4, 10);
+        assert!(r.to_string() == "Helld!");
+    }
+
+    #[test]
+    fn test_remove_multi_node() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("world!");
+        r.insert_copy(0, ">");
+        r.push_copy("<");
+        r.remove(1, 13);
+        assert!(r.to_string() == "><");
+        assert!(r.len() == 2);
+
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("world!");
+        r.insert_copy(0, ">");
+        r.push_copy("<");
+        r.remove(3, 13);
+        assert!(r.to_string() == ">He<");
+        assert!(r.len() == 4);
+    }
+
+    #[test]
+    fn test_insert_copy() {
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.insert_copy(0, "foo");
+        assert!(r.to_string() == "fooHello world!");
+        assert!(r.slice(2..8).to_string() == "oHello");
+
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.insert_copy(12, "foo");
+        assert!(r.to_string() == "Hello world!foo");
+        assert!(r.slice(2..8).to_string() == "llo wo");
+
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.insert_copy(5, "foo");
+        assert!(r.to_string() == "Hellofoo world!");
+        assert!(r.slice(2..8).to_string() == "llofoo");
+    }
+
+    #[test]
+    fn test_push_copy() {
+        let mut r: Rope = "Hello world!".parse().unwrap();
+        r.push_copy("foo");
+        assert!(r.to_string() == "Hello world!foo");
+        assert!(r.slice(2..8).to_string() == "llo wo");
+    }
+
+    #[test]
+    fn test_insert_replace() {
+        let mut r: Rope = "hello worl\u{00bb0}!".parse().unwrap();
+        r.insert_copy(5, "bb");
+        assert!(r.to_string() == "hellobb worlர!");
+        r.replace(0, 'H');
+        r.replace(15, '~');
+        r.replace_str(5, "fo\u{00cb0}");
+        assert!(r.to_string() == "Hellofoರrlர~");
+        assert!(r.slice(0..10).to_string() == "Hellofoರ");
+        assert!(r.slice(5..10).to_string() == "foರ");
+        assert!(r.slice(10..15).to_string() == "rlர");
+
+        let expected = "Hellofoರrlர~";
+        let mut byte_pos = 0;
+        for ((c, b), e) in r.chars().zip(expected.chars()) {
+            assert!(c == e);
+            assert!(b == byte_pos);
+            byte_pos += e.len_utf8();
+        }
+    }
+
+    #[test]
+    fn test_slice_iter() {
+        let mut r: Rope = "Helloworld!".parse().unwrap();
+        r.insert_copy(5, " ");
+
+        let mut slice = r.slice(3..8).iter_chars();
+
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(Some(('o', 4)), slice.next());
+        assert_eq!(Some((' ', 5)), slice.next());
+        assert_eq!(Some(('w', 6)), slice.next());
+        assert_eq!(Some(('o', 7)), slice.next());
+        assert_eq!(Some(('r', 8)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_slice_from_start() {
+        let mut r: Rope = "Helloworld!".parse().unwrap();
+        r.insert_copy(5, " ");
+
+        let mut slice = r.slice(0..6).iter_chars();
+
+        assert_eq!(Some(('H', 0)), slice.next());
+        assert_eq!(Some(('e', 1)), slice.next());
+        assert_eq!(Some(('l', 2)), slice.next());
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(Some(('o', 4)), slice.next());
+        assert_eq!(Some((' ', 5)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_slice_iter_no_split() {
+        let r: Rope = "Hello world!".parse().unwrap();
+
+        let mut slice = r.slice(3..8).iter_chars();
+
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(Some(('o', 4)), slice.next());
+        assert_eq!(Some((' ', 5)), slice.next());
+        assert_eq!(Some(('w', 6)), slice.next());
+        assert_eq!(Some(('o', 7)), slice.next());
+        assert_eq!(Some(('r', 8)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_slice_iter_special_chars() {
+        let r: Rope = "Hello©world".parse().unwrap();
+        let mut slice = r.slice(3..8).iter_chars();
+
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(Some(('o', 4)), slice.next());
+        assert_eq!(Some(('\u{a9}', 5)), slice.next());
+        assert_eq!(Some(('w', 7)), slice.next());
+        assert_eq!(Some(('o', 8)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_slice_iter_special_chars_split() {
+        let mut r: Rope = "Helloworld".parse().unwrap();
+        r.insert_copy(5, "©");
+
+        let mut slice = r.slice(3..8).iter_chars();
+
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(Some(('o', 4)), slice.next());
+        assert_eq!(Some(('\u{a9}', 5)), slice.next());
+        assert_eq!(Some(('w', 7)), slice.next());
+        assert_eq!(Some(('o', 8)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_small_slice_single_node() {
+        let r: Rope = "Hello world".parse().unwrap();
+        let mut slice = r.slice(1..3).iter_chars();
+
+        assert_eq!(Some(('e', 1)), slice.next());
+        assert_eq!(Some(('l', 2)), slice.next());
+        assert_eq!(Some(('l', 3)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_small_slice_start_single_node() {
+        let r: Rope = "Hello world".parse().unwrap();
+        let mut slice = r.slice(0..1).iter_chars();
+
+        assert_eq!(Some(('H', 0)), slice.next());
+        assert_eq!(Some(('e', 1)), slice.next());
+        assert_eq!(None, slice.next());
+    }
+
+    #[test]
+    fn test_char_line_conversions() {
+        let mut r: Rope = "h\u{e9}llo\nw\u{f6}r".parse().unwrap();
+        r.push_copy("ld\n\u{b0}\u{b0}\n");
+        // "héllo\n" is 6 chars, "wörld\n" 6 chars, "°°\n" 3 chars.
+        assert_eq!(4, r.len_lines());
+
+        assert_eq!(0, r.char_to_line(0));
+        assert_eq!(0, r.char_to_line(5));
+        assert_eq!(1, r.char_to_line(6));
+        assert_eq!(1, r.char_to_line(11));
+        assert_eq!(2, r.char_to_line(12));
+        assert_eq!(3, r.char_to_line(15));
+
+        assert_eq!(0, r.line_to_char(0));
+        assert_eq!(6, r.line_to_char(1));
+        assert_eq!(12, r.line_to_char(2));
+        assert_eq!(15, r.line_to_char(3));
+        assert_eq!(15, r.line_to_char(4));
+
+        for line in 0..r.len_lines() {
+            assert_eq!(line, r.char_to_line(r.line_to_char(line)));
+        }
+    }
+
+    #[test]
+    fn test_byte_line_conversions() {
+        let mut r: Rope = "h\u{e9}llo\nw\u{f6}r".parse().unwrap();
+        r.push_copy("ld\n\u{b0}\u{b0}\n");
+
+        assert_eq!(0, r.byte_to_line(0));
+        assert_eq!(0, r.byte_to_line(6));
+        assert_eq!(1, r.byte_to_line(7));
+        assert_eq!(2, r.byte_to_line(14));
+        assert_eq!(3, r.byte_to_line(r.len()));
+
+        assert_eq!(7, r.line_to_byte(1));
+        assert_eq!(14, r.line_to_byte(2));
+        assert_eq!(r.len(), r.line_to_byte(3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_line_to_char_out_of_bounds() {
+        let r: Rope = "foo\nbar".parse().unwrap();
+        r.line_to_char(3);
+    }
+
+    #[test]
+    fn test_diff_range() {
+        let mut a: Rope = "Hello ".parse().unwrap();
+        a.push_copy("world!");
+        let b: Rope = "Hello there world!".parse().unwrap();
+        assert_eq!(Some(6..6), a.diff_range(&b));
+        assert_eq!(Some(6..12), b.diff_range(&a));
+
+        // Differ only in the middle.
+        let b: Rope = "Hello w\u{f6}rld!".parse().unwrap();
+        assert_eq!(Some(7..8), a.diff_range(&b));
+        assert_eq!(Some(7..9), b.diff_range(&a));
+
+        // Differ at the start.
+        let b: Rope = "Jello world!".parse().unwrap();
+        assert_eq!(Some(0..1), a.diff_range(&b));
+
+        // Identical.
+        let b: Rope = "Hello world!".parse().unwrap();
+        assert_eq!(None, a.diff_range(&b));
+        assert_eq!(None, Rope::new().diff_range(&Rope::new()));
+    }
+
+    #[test]
+    fn test_diff_range_char_boundaries() {
+        // \u{e9} and \u{e8} share their first byte.
+        let a: Rope = "caf\u{e9}s".parse().unwrap();
+        let b: Rope = "caf\u{e8}s".parse().unwrap();
+        assert_eq!(Some(3..5), a.diff_range(&b));
+
+        // \u{1e9} and \u{e9} share their last byte.
+        let a: Rope = "a\u{1e9}".parse().unwrap();
+        let b: Rope = "a\u{e9}".parse().unwrap();
+        assert_eq!(Some(1..3), a.diff_range(&b));
+    }
+
+    #[test]
+    fn test_segment_size() {
+        let text = "Hello w\u{f6}rld! H\u{e9}llo \u{2603}!";
+
+        let r = Rope::from_string(text.to_string());
+        assert_eq!(1, count_leaves(&r));
+
+        let mut counts = vec![];
+        for &size in &[1, 2, 3, 5, 16, 64] {
+            let mut r = Rope::new_with_segment_size(size);
+            r.insert_copy(0, text);
+            assert!(r.to_string() == text);
+            assert!(r.len() == text.len());
+            let chars: Vec<_> = r.chars().map(|(c, _)| c).collect();
+            assert!(chars == text.chars().collect::<Vec<_>>());
+            counts.push(count_leaves(&r));
+        }
+        // A segment size of 1 gives one leaf per char.
+        assert_eq!(text.chars().count(), counts[0]);
+        assert!(counts.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(1, counts[5]);
+    }
+
+    #[test]
+    fn test_segment_size_edit() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.insert_copy(0, "Hello world!");
+        r.insert_copy(6, "\u{e9}\u{e9}\u{e9} ");
+        r.remove(2, 4);
+        assert!(r.to_string() == "Heo \u{e9}\u{e9}\u{e9} world!");
+        assert!(r.slice(3..11).to_string() == " \u{e9}\u{e9}\u{e9} ");
+    }
+
+    #[test]
+    fn test_make_ascii_case() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.insert_copy(0, "Hello W\u{f6}rld, \u{c9}t\u{e9}!");
+        r.push_copy(" ab\u{3b1}\u{3b2}");
+        let len = r.len();
+
+        r.make_ascii_uppercase();
+        assert!(r.to_string() == "HELLO W\u{f6}RLD, \u{c9}T\u{e9}! AB\u{3b1}\u{3b2}");
+        assert_eq!(len, r.len());
+
+        r.make_ascii_lowercase();
+        assert!(r.to_string() == "hello w\u{f6}rld, \u{c9}t\u{e9}! ab\u{3b1}\u{3b2}");
+        assert_eq!(len, r.len());
+
+        let mut r = Rope::new();
+        r.make_ascii_uppercase();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut r = Rope::new();
+        r.push_copy("a\u{e9}");
+        r.push_copy("\u{2603}b");
+        // a: 0, \u{e9}: 1..3, \u{2603}: 3..6, b: 6, end: 7
+
+        assert_eq!(0, r.advance(0, 0));
+        assert_eq!(1, r.advance(0, 1));
+        assert_eq!(3, r.advance(0, 2));
+        assert_eq!(6, r.advance(1, 2));
+        assert_eq!(7, r.advance(3, 2));
+        assert_eq!(7, r.advance(0, 100));
+        assert_eq!(7, r.advance(7, 1));
+
+        assert_eq!(6, r.advance(7, -1));
+        assert_eq!(3, r.advance(6, -1));
+        assert_eq!(1, r.advance(6, -2));
+        assert_eq!(0, r.advance(3, -2));
+        assert_eq!(0, r.advance(7, -100));
+        assert_eq!(0, r.advance(0, -1));
+
+        let empty = Rope::new();
+        assert_eq!(0, empty.advance(0, 1));
+        assert_eq!(0, empty.advance(0, -1));
+    }
+
+    #[test]
+    fn test_from_lines() {
+        let r = Rope::from_lines(["a", "b", "c"]);
+        assert!(r.to_string() == "a\nb\nc");
+        assert_eq!(3, r.len_lines());
+
+        let lines = vec!["fn foo() {".to_string(), "".to_string(), "    \u{2603}".to_string(), "}".to_string()];
+        let r = Rope::from_lines(&lines);
+        let round_trip: Vec<String> = r.lines().map(|l| l.to_string()).collect();
+        assert!(round_trip == lines);
+
+        let r = Rope::from_lines(Vec::<String>::new());
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut r: Rope = "foo\nb".parse().unwrap();
+        r.push_copy("ar\n\nbaz\n");
+        let lines: Vec<String> = r.lines().map(|l| l.to_string()).collect();
+        assert!(lines == ["foo", "bar", "", "baz", ""]);
+        assert_eq!(r.len_lines(), lines.len());
+
+        let lines: Vec<String> = Rope::new().lines().map(|l| l.to_string()).collect();
+        assert!(lines == [""]);
+    }
+
+    #[test]
+    fn test_get_char_slice() {
+        let mut r: Rope = "h\u{e9}l".parse().unwrap();
+        r.push_copy("lo \u{2603}!");
+        // 8 chars, 11 bytes.
+
+        assert!(r.get_char_slice(0..8).unwrap().to_string() == "h\u{e9}llo \u{2603}!");
+        assert!(r.get_char_slice(1..4).unwrap().to_string() == "\u{e9}ll");
+        assert!(r.get_char_slice(6..7).unwrap().to_string() == "\u{2603}");
+        assert!(r.get_char_slice(8..8).unwrap().to_string() == "");
+
+        assert!(r.get_char_slice(0..9).is_none());
+        assert!(r.get_char_slice(6..11).is_none());
+        assert!(r.get_char_slice(9..9).is_none());
+    }
+
+    #[test]
+    fn test_insert_smart_newline() {
+        let mut r: Rope = "fn foo() {\n    \tlet x = 1;\n}".parse().unwrap();
+        let at = r.to_string().find(" = ").unwrap();
+        let cursor = r.insert_smart_newline(at);
+        assert!(r.to_string() == "fn foo() {\n    \tlet x\n    \t = 1;\n}");
+        assert_eq!(at + 6, cursor);
+        assert_eq!(2, r.byte_to_line(cursor));
+        assert!(r.slice(r.line_to_byte(2)..cursor).to_string() == "    \t");
+
+        // No indentation.
+        let mut r: Rope = "foo\nbar".parse().unwrap();
+        assert_eq!(6, r.insert_smart_newline(5));
+        assert!(r.to_string() == "foo\nb\nar");
+
+        // Within the indentation.
+        let mut r: Rope = "    foo".parse().unwrap();
+        assert_eq!(5, r.insert_smart_newline(2));
+        assert!(r.to_string() == "  \n    foo");
+    }
+
+    #[test]
+    fn test_byte_span() {
+        let mut r: Rope = "a\u{e9}".parse().unwrap();
+        r.push_copy("\u{2603}b\u{1f600}");
+
+        assert_eq!(0, r.byte_span(2..2));
+        assert_eq!(1, r.byte_span(0..1));
+        assert_eq!(6, r.byte_span(0..3));
+        assert_eq!(4, r.byte_span(2..4));
+        assert_eq!(r.len(), r.byte_span(0..5));
+        assert!(r.byte_span(1..5) > 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_byte_span_out_of_bounds() {
+        let r: Rope = "a\u{e9}".parse().unwrap();
+        r.byte_span(1..3);
+    }
+
+    #[test]
+    fn test_is_blank() {
+        assert!(Rope::new().is_blank());
+
+        let mut r: Rope = " \t\n".parse().unwrap();
+        r.push_copy("\u{3000}\r\n ");
+        assert!(r.is_blank());
+
+        r.insert_copy(3, "\u{e9}");
+        assert!(!r.is_blank());
+
+        let r: Rope = "  x  ".parse().unwrap();
+        assert!(!r.is_blank());
+
+        // An ideographic space split between leaves by a removal.
+        let mut r: Rope = "\u{3000}\u{3000}".parse().unwrap();
+        r.remove(1, 4);
+        assert!(r.has_split_chars());
+        assert!(r.is_blank());
+        assert!(!split_char_rope().is_blank());
+    }
+
+    #[test]
+    fn test_lines_common_prefix() {
+        let mut r: Rope = "fn foo() {\n        let x = 1;\n    ".parse().unwrap();
+        r.push_copy("    // \u{2603}\n        // \u{2603}\u{2603}\n        // \u{e9}\n}");
+
+        assert!(r.lines_common_prefix(0..0).is_empty());
+        assert!(r.lines_common_prefix(1..2) == "        let x = 1;");
+        assert!(r.lines_common_prefix(1..3) == "        ");
+        assert!(r.lines_common_prefix(2..4) == "        // \u{2603}");
+        assert!(r.lines_common_prefix(2..5) == "        // ");
+        assert!(r.lines_common_prefix(1..5) == "        ");
+        assert!(r.lines_common_prefix(0..6).is_empty());
+    }
+
+    #[test]
+    fn test_splice() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("world!");
+        r.splice(4..8, "\u{f6} w\u{f6}");
+        assert!(r.to_string() == "Hell\u{f6} w\u{f6}rld!");
+        r.splice(0..0, ">");
+        r.splice(r.len()..r.len(), "<");
+        assert!(r.to_string() == ">Hell\u{f6} w\u{f6}rld!<");
+        r.splice(1..r.len() - 1, "");
+        assert!(r.to_string() == "><");
+    }
+
+    #[test]
+    fn test_replace_char_range() {
+        let mut r: Rope = "h\u{e9}llo ".parse().unwrap();
+        r.push_copy("\u{2603}\u{2603} w\u{f6}rld");
+
+        r.replace_char_range(6..8, "snowmen");
+        assert!(r.to_string() == "h\u{e9}llo snowmen w\u{f6}rld");
+
+        r.replace_char_range(1..5, "\u{1f600}");
+        assert!(r.to_string() == "h\u{1f600} snowmen w\u{f6}rld");
+
+        r.replace_char_range(10..16, "");
+        assert!(r.to_string() == "h\u{1f600} snowmen");
+    }
+
+    #[test]
+    fn test_split_line_at() {
+        let mut r: Rope = "first\nsec".parse().unwrap();
+        r.push_copy("ond line\nthird");
+
+        let (before, after) = r.split_line_at(9);
+        assert!(before.to_string() == "sec");
+        assert!(after.to_string() == "ond line");
+
+        let (before, after) = r.split_line_at(6);
+        assert!(before.to_string() == "");
+        assert!(after.to_string() == "second line");
+
+        let (before, after) = r.split_line_at(17);
+        assert!(before.to_string() == "second line");
+        assert!(after.to_string() == "");
+
+        let (before, after) = r.split_line_at(r.len());
+        assert!(before.to_string() == "third");
+        assert!(after.to_string() == "");
+
+        for i in 0..r.len() + 1 {
+            let (before, after) = r.split_line_at(i);
+            assert!(!before.to_string().contains('\n'));
+            assert!(!after.to_string().contains('\n'));
+        }
+    }
+
+    #[test]
+    fn test_chars_rev() {
+        let mut r = Rope::new_with_segment_size(2);
+        r.insert_copy(0, "h\u{e9}llo \u{2603}");
+        r.push_copy("\u{1f600}!");
+        r.remove(1, 3);
+        let text = r.to_string();
+
+        let expected: Vec<_> = text.char_indices().rev().map(|(b, c)| (c, b)).collect();
+        let actual: Vec<_> = r.chars_rev().collect();
+        assert!(actual == expected);
+
+        assert_eq!(None, Rope::new().chars_rev().next());
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("w\u{f6}rld!");
+        let tail = r.split_off(3);
+        assert!(r.to_string() == "Hel");
+        assert!(tail.to_string() == "lo w\u{f6}rld!");
+        assert!(tail.len() == 10);
+        r.assert_pointers_valid();
+        tail.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_split_off_at_line() {
+        let text = "one\ntw\u{f6}\nthree\n";
+
+        let mut r: Rope = text.parse().unwrap();
+        let tail = r.split_off_at_line(0);
+        assert!(r.to_string() == "");
+        assert!(tail.to_string() == text);
+
+        let mut r: Rope = "one\ntw".parse().unwrap();
+        r.push_copy("\u{f6}\nthree\n");
+        let tail = r.split_off_at_line(1);
+        assert!(r.to_string() == "one\n");
+        assert!(tail.to_string() == "tw\u{f6}\nthree\n");
+        assert_eq!(3, tail.len_lines());
+
+        let mut r: Rope = text.parse().unwrap();
+        let tail = r.split_off_at_line(3);
+        assert!(r.to_string() == text);
+        assert!(tail.to_string() == "");
+
+        let mut r: Rope = text.parse().unwrap();
+        let tail = r.split_off_at_line(10);
+        assert!(r.to_string() == text);
+        assert!(tail.to_string() == "");
+    }
+
+    #[test]
+    fn test_fold_bytes() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("w\u{f6}rld!");
+        r.insert_copy(6, "\u{2603} ");
+
+        let sum = r.fold_bytes(0u64, |acc, b| acc + b as u64);
+        let expected = r.to_string().bytes().fold(0u64, |acc, b| acc + b as u64);
+        assert_eq!(expected, sum);
+
+        assert_eq!(7, Rope::new().fold_bytes(7, |acc, _| acc + 1));
+    }
+
+    #[cfg(feature = "digest")]
+    #[test]
+    fn test_digest() {
+        let text = "Hello w\u{f6}rld! \u{2603}\u{2603}\u{2603}";
+
+        let a: Rope = text.parse().unwrap();
+        let mut b = Rope::new_with_segment_size(3);
+        b.insert_copy(0, "w\u{f6}rld! \u{2603}\u{2603}");
+        b.insert_copy(0, "Hello ");
+        b.push_copy("\u{2603}");
+        assert!(b.to_string() == text);
+        assert!(count_leaves(&a) != count_leaves(&b));
+        assert_eq!(a.digest(), b.digest());
+
+        b.replace(0, 'J');
+        assert!(a.digest() != b.digest());
+    }
+
+    #[test]
+    fn test_len_chars() {
+        let mut r: Rope = "h\u{e9}llo".parse().unwrap();
+        r.push_copy(" \u{1f600}");
+        assert_eq!(7, r.len_chars());
+        assert_eq!(0, Rope::new().len_chars());
+    }
+
+    #[test]
+    fn test_map_chars() {
+        let mut r: Rope = "h\u{e9}llo".parse().unwrap();
+        r.push_copy(" \u{2603}\u{1f600}");
+
+        let code_points = r.map_chars(|c| c as u32);
+        let expected: Vec<u32> = r.to_string().chars().map(|c| c as u32).collect();
+        assert!(code_points == expected);
+        assert_eq!(8, code_points.len());
+
+        assert_eq!(split_char_rope().map_chars(|c| c), ['a', '\u{20ac}', 'b']);
+        let r = rope_with_leaves_of("\u{1f600}\u{e9}x\u{2603}", 3);
+        assert_eq!(r.map_chars(|c| c.len_utf8()), [4, 2, 1, 3]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("world!");
+        r.truncate(20);
+        assert!(r.to_string() == "Hello world!");
+        r.truncate(8);
+        assert!(r.to_string() == "Hello wo");
+        assert!(r.len() == 8);
+        r.truncate(0);
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_truncate_to_boundary() {
+        let mut r: Rope = "a\u{e9}".parse().unwrap();
+        r.push_copy("\u{2603}b");
+        // a: 0, \u{e9}: 1..3, \u{2603}: 3..6, b: 6
+
+        r.truncate_to_boundary(100);
+        assert!(r.to_string() == "a\u{e9}\u{2603}b");
+        r.truncate_to_boundary(5);
+        assert!(r.to_string() == "a\u{e9}");
+        assert!(r.len() == 3);
+        r.truncate_to_boundary(3);
+        assert!(r.to_string() == "a\u{e9}");
+        r.truncate_to_boundary(2);
+        assert!(r.to_string() == "a");
+        r.truncate_to_boundary(0);
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_byte_at() {
+        let mut r: Rope = "one\ntw".parse().unwrap();
+        r.push_copy("\u{f6}\n\nfour");
+
+        assert_eq!(Some(0), r.byte_at(0, 0));
+        assert_eq!(Some(2), r.byte_at(0, 2));
+        assert_eq!(Some(3), r.byte_at(0, 3));
+        assert_eq!(None, r.byte_at(0, 4));
+        assert_eq!(Some(6), r.byte_at(1, 2));
+        assert_eq!(Some(8), r.byte_at(1, 4));
+        assert_eq!(None, r.byte_at(1, 5));
+        assert_eq!(Some(9), r.byte_at(2, 0));
+        assert_eq!(None, r.byte_at(2, 1));
+        assert_eq!(Some(12), r.byte_at(3, 2));
+        assert_eq!(Some(14), r.byte_at(3, 4));
+        assert_eq!(None, r.byte_at(3, 5));
+        assert_eq!(None, r.byte_at(4, 0));
+
+        assert_eq!(Some(0), Rope::new().byte_at(0, 0));
+        assert_eq!(None, Rope::new().byte_at(0, 1));
+    }
+
+    #[test]
+    fn test_segments_in() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.insert_copy(0, "abcdefgh\u{e9}ijk");
+
+        let segments: Vec<_> = r.segments_in(2..11).collect();
+        assert!(segments == [("cd", 2..4), ("efgh", 4..8), ("\u{e9}i", 8..11)]);
+
+        let segments: Vec<_> = r.segments_in(5..7).collect();
+        assert!(segments == [("fg", 5..7)]);
+
+        let text: String = r.segments_in(0..r.len()).map(|(s, _)| s).collect();
+        assert!(text == r.to_string());
+
+        assert_eq!(0, r.segments_in(3..3).count());
+    }
+
+    #[test]
+    fn test_replace_char() {
+        let mut r: Rope = "h\u{e9}llo ".parse().unwrap();
+        r.push_copy("w\u{f6}rld");
+
+        // Same width, overwritten in place.
+        let buffers = r.storage.len();
+        r.replace_char(1, '\u{f6}');
+        r.replace_char(0, 'H');
+        r.replace_char(8, '\u{e9}');
+        assert!(r.to_string() == "H\u{f6}llo w\u{e9}rld");
+        assert_eq!(buffers, r.storage.len());
+
+        // Different widths.
+        r.replace_char(1, 'e');
+        assert!(r.to_string() == "Hello w\u{e9}rld");
+        r.replace_char(7, '\u{2603}');
+        assert!(r.to_string() == "Hello w\u{2603}rld");
+        r.replace_char(10, '!');
+        assert!(r.to_string() == "Hello w\u{2603}!ld");
+        assert!(r.len() == 13);
+        assert!(r.storage.len() > buffers);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_replace_char_mid_char() {
+        let mut r: Rope = "h\u{e9}llo".parse().unwrap();
+        r.replace_char(2, 'e');
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_replace_char_out_of_bounds() {
+        let mut r: Rope = "h\u{e9}llo".parse().unwrap();
+        r.replace_char(6, 'e');
+    }
+
+    #[test]
+    fn test_line_slices() {
+        let text = "first line\nsec\u{f6}nd\n\n    fourth \u{2603}\nlast";
+        let mut r = Rope::new_with_segment_size(5);
+        r.insert_copy(0, text);
+
+        let slices = r.line_slices();
+        assert_eq!(r.len_lines(), slices.len());
+        for (slice, line) in slices.iter().zip(text.split('\n')) {
+            assert!(slice.to_string() == line);
+        }
+    }
+
+    #[test]
+    fn test_char_at() {
+        let mut r: Rope = "h\u{e9}llo\nw\u{f6}".parse().unwrap();
+        r.push_copy("rld \u{2603}\n\n\u{1f600}");
+
+        assert_eq!(Some('h'), r.char_at(0, 0));
+        assert_eq!(Some('\u{e9}'), r.char_at(0, 1));
+        assert_eq!(Some('o'), r.char_at(0, 4));
+        assert_eq!(None, r.char_at(0, 5));
+        assert_eq!(Some('\u{f6}'), r.char_at(1, 1));
+        assert_eq!(Some('r'), r.char_at(1, 2));
+        assert_eq!(Some('\u{2603}'), r.char_at(1, 6));
+        assert_eq!(None, r.char_at(1, 7));
+        assert_eq!(None, r.char_at(2, 0));
+        assert_eq!(Some('\u{1f600}'), r.char_at(3, 0));
+        assert_eq!(None, r.char_at(3, 1));
+        assert_eq!(None, r.char_at(4, 0));
+    }
+
+    #[test]
+    fn test_load() {
+        let mut text = String::new();
+        for i in 0..1000 {
+            text.push_str(&format!("line {} \u{e9}\u{2603}\u{1f600}\n", i));
+        }
+
+        let r = Rope::load(text.as_bytes(), 16).unwrap();
+        assert!(r.to_string() == text);
+        assert!(r.len() == text.len());
+        let leaves = count_leaves(&r);
+        assert!(leaves >= text.len() / 19);
+        // A balanced tree has depth log2(leaves) + 1.
+        let mut max_depth = 1;
+        while 1 << (max_depth - 1) < leaves {
+            max_depth += 1;
+        }
+        assert!(depth(&r) <= max_depth);
+
+        let mut r = r;
+        r.insert_copy(5, "foo");
+        r.remove(0, 3);
+        assert!(r.slice(0..6).to_string() == "e foo0");
+
+        let r = Rope::load("".as_bytes(), 16).unwrap();
+        assert!(r.to_string() == "");
+        let r = Rope::load("\u{e9}".as_bytes(), 1).unwrap();
+        assert!(r.to_string() == "\u{e9}");
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        assert!(Rope::load(&b"abc\xffdef"[..], 2).is_err());
+        assert!(Rope::load(&b"abc\xc3"[..], 2).is_err());
+    }
+
+    #[test]
+    fn test_char_indices_rev() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.insert_copy(0, "a\u{e9}b\u{2603}");
+        r.push_copy("\u{1f600}c");
+        let text = r.to_string();
+
+        let expected: Vec<_> = text.char_indices().rev().collect();
+        let actual: Vec<_> = r.char_indices_rev().collect();
+        assert!(actual == expected);
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        let mut r: Rope = "one\r\ntwo\rthree\nfour\r".parse().unwrap();
+        r.push_copy("\nfive\n\r\n\r");
+        let lines = 8;
+
+        r.normalize_newlines(LineEnding::Lf);
+        assert!(r.to_string() == "one\ntwo\nthree\nfour\nfive\n\n\n");
+        assert_eq!(lines, r.len_lines());
+
+        r.normalize_newlines(LineEnding::CrLf);
+        assert!(r.to_string() == "one\r\ntwo\r\nthree\r\nfour\r\nfive\r\n\r\n\r\n");
+        r.normalize_newlines(LineEnding::CrLf);
+        assert!(r.to_string() == "one\r\ntwo\r\nthree\r\nfour\r\nfive\r\n\r\n\r\n");
+
+        r.normalize_newlines(LineEnding::Cr);
+        assert!(r.to_string() == "one\rtwo\rthree\rfour\rfive\r\r\r");
+        r.normalize_newlines(LineEnding::Lf);
+        assert!(r.to_string() == "one\ntwo\nthree\nfour\nfive\n\n\n");
+    }
+
+    #[test]
+    fn test_insert_str_checked() {
+        let mut r: Rope = "h\u{e9}llo".parse().unwrap();
+        r.push_copy(" w\u{f6}rld");
+
+        assert_eq!(Err(RopeError::OutOfBounds { offset: 14, len: 13 }),
+                   r.insert_str_checked(14, "foo"));
+        assert_eq!(Err(RopeError::NotCharBoundary(2)), r.insert_str_checked(2, "foo"));
+        assert_eq!(Err(RopeError::NotCharBoundary(9)), r.insert_str_checked(9, "foo"));
+        assert!(r.to_string() == "h\u{e9}llo w\u{f6}rld");
+        assert!(r.len() == 13);
+
+        assert_eq!(Ok(()), r.insert_str_checked(3, "\u{2603}"));
+        assert_eq!(Ok(()), r.insert_str_checked(0, "<"));
+        assert_eq!(Ok(()), r.insert_str_checked(r.len(), ">"));
+        assert!(r.to_string() == "<h\u{e9}\u{2603}llo w\u{f6}rld>");
+
+        let mut r = Rope::new();
+        assert_eq!(Ok(()), r.insert_str_checked(0, "foo"));
+        assert!(r.to_string() == "foo");
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut r: Rope = "Hello ".parse().unwrap();
+        r.push_copy("w\u{f6}rld!");
+        r.remove(2, 12);
+        assert!(r.capacity() >= 13);
+        r.shrink_to_fit();
+        assert!(r.to_string() == "He!");
+        assert_eq!(3, r.capacity());
+        assert_eq!(1, r.storage.len());
+        r.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_auto_compact() {
+        let text = "0123456789".repeat(10);
+        let mut r: Rope = text.parse().unwrap();
+        r.set_auto_compact(0.5);
+
+        r.remove(0, 40);
+        assert_eq!(100, r.capacity());
+        r.remove(0, 10);
+        assert_eq!(100, r.capacity());
+        // Now 49 of 100 bytes are live.
+        r.remove(0, 1);
+        assert_eq!(49, r.capacity());
+        assert!(r.to_string() == text[51..]);
+
+        r.remove(0, 20);
+        assert_eq!(49, r.capacity());
+        r.remove(0, 5);
+        assert_eq!(24, r.capacity());
+        assert!(r.to_string() == text[76..]);
+        r.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_char_windows() {
+        let mut r = Rope::new_with_segment_size(2);
+        r.insert_copy(0, "h\u{e9}llo");
+        r.push_copy(" \u{2603}!");
+        let chars: Vec<char> = r.to_string().chars().collect();
+
+        for n in 1..10 {
+            let expected: Vec<Vec<char>> = chars.windows(n).map(|w| w.to_vec()).collect();
+            let actual: Vec<Vec<char>> = r.char_windows(n).collect();
+            assert!(actual == expected);
+        }
+
+        assert_eq!(0, Rope::new().char_windows(1).count());
+
+        // Chars split between leaves are stitched together.
+        let windows: Vec<Vec<char>> = split_char_rope().char_windows(2).collect();
+        assert_eq!(windows, [['a', '\u{20ac}'], ['\u{20ac}', 'b']]);
+        let text = "\u{1f600}x\u{e9}\u{2603}";
+        let r = rope_with_leaves_of(text, 3);
+        let chars: Vec<char> = text.chars().collect();
+        let expected: Vec<Vec<char>> = chars.windows(3).map(|w| w.to_vec()).collect();
+        assert!(r.char_windows(3).collect::<Vec<_>>() == expected);
+    }
+
+    #[test]
+    fn test_viewport() {
+        let mut r: Rope = "zero\none\ntw".parse().unwrap();
+        r.push_copy("o\nthree\nfour");
+
+        assert!(r.viewport(1, 2).to_string() == "one\ntwo\n");
+        assert!(r.viewport(0, 1).to_string() == "zero\n");
+        assert!(r.viewport(2, 0).to_string() == "");
+        assert!(r.viewport(3, 10).to_string() == "three\nfour");
+        assert!(r.viewport(4, 1).to_string() == "four");
+        assert!(r.viewport(5, 1).to_string() == "");
+        assert!(r.viewport(0, usize::MAX).to_string() == r.to_string());
+    }
+
+    #[test]
+    fn test_line_byte_range() {
+        let mut r: Rope = "zero\n\u{f6}ne\n".parse().unwrap();
+        r.push_copy("\nthree");
+
+        assert_eq!(Some(0..4), r.line_byte_range(0));
+        assert_eq!(Some(5..9), r.line_byte_range(1));
+        assert_eq!(Some(10..10), r.line_byte_range(2));
+        assert_eq!(Some(11..16), r.line_byte_range(3));
+        assert_eq!(None, r.line_byte_range(4));
+
+        r.push_copy("\n");
+        assert_eq!(Some(11..16), r.line_byte_range(3));
+        assert_eq!(Some(17..17), r.line_byte_range(4));
+        assert_eq!(None, r.line_byte_range(5));
+    }
+
+    #[cfg(feature = "grapheme")]
+    #[test]
+    fn test_grapheme_count_in() {
+        // "e" followed by a combining acute accent is one grapheme.
+        let mut r: Rope = "cafe\u{301} ".parse().unwrap();
+        r.push_copy("na\u{308}ive");
+
+        assert_eq!(12, r.len_chars());
+        assert_eq!(10, r.grapheme_count_in(0..r.len()));
+        assert_eq!(4, r.grapheme_count_in(0..6));
+        assert_eq!(2, r.grapheme_count_in(7..11));
+        assert_eq!(0, r.grapheme_count_in(3..3));
+    }
+
+    #[test]
+    fn test_split_once() {
+        let mut r: Rope = "k\u{e9}y".parse().unwrap();
+        r.push_copy("=val=ue");
+        let (before, after) = r.split_once('=').unwrap();
+        assert!(before.to_string() == "k\u{e9}y");
+        assert!(after.to_string() == "val=ue");
+
+        // Across a leaf boundary.
+        let mut r: Rope = "key \u{2192}".parse().unwrap();
+        r.push_copy(" value");
+        let (before, after) = r.split_once('\u{2192}').unwrap();
+        assert!(before.to_string() == "key ");
+        assert!(after.to_string() == " value");
+
+        let (before, after) = r.split_once('k').unwrap();
+        assert!(before.to_string() == "");
+        assert!(after.to_string() == "ey \u{2192} value");
+
+        assert!(r.split_once(':').is_none());
+        assert!(Rope::new().split_once(':').is_none());
+    }
+
+    #[test]
+    fn test_rsplit_once() {
+        let mut r: Rope = "archive.t".parse().unwrap();
+        r.push_copy("ar.gz");
+        let (before, after) = r.rsplit_once('.').unwrap();
+        assert!(before.to_string() == "archive.tar");
+        assert!(after.to_string() == "gz");
+
+        let mut r: Rope = "a\u{2192}b".parse().unwrap();
+        r.push_copy("\u{2192}c\u{e9}");
+        let (before, after) = r.rsplit_once('\u{2192}').unwrap();
+        assert!(before.to_string() == "a\u{2192}b");
+        assert!(after.to_string() == "c\u{e9}");
+
+        let (before, after) = r.rsplit_once('\u{e9}').unwrap();
+        assert!(before.to_string() == "a\u{2192}b\u{2192}c");
+        assert!(after.to_string() == "");
+
+        assert!(r.rsplit_once('.').is_none());
+    }
+
+    #[test]
+    fn test_strip_prefix_suffix() {
+        let mut r: Rope = "// h\u{e9}".parse().unwrap();
+        r.push_copy("llo w\u{f6}rld;");
+
+        // Across a leaf boundary.
+        assert!(r.strip_prefix("// h\u{e9}ll").unwrap().to_string() == "o w\u{f6}rld;");
+        assert!(r.strip_suffix("\u{e9}llo w\u{f6}rld;").unwrap().to_string() == "// h");
+        // Within a leaf.
+        assert!(r.strip_prefix("// ").unwrap().to_string() == "h\u{e9}llo w\u{f6}rld;");
+        assert!(r.strip_suffix(";").unwrap().to_string() == "// h\u{e9}llo w\u{f6}rld");
+        // Everything or nothing.
+        assert!(r.strip_prefix("").unwrap().to_string() == r.to_string());
+        assert!(r.strip_suffix(&r.to_string()).unwrap().to_string() == "");
+
+        assert!(r.strip_prefix("/* ").is_none());
+        assert!(r.strip_prefix("// he").is_none());
+        assert!(r.strip_suffix("world;").is_none());
+        assert!(r.strip_suffix("x// h\u{e9}llo w\u{f6}rld;").is_none());
+    }
+
+    #[test]
+    fn test_char_at_or_before() {
+        let mut r: Rope = "a\u{2603}".parse().unwrap();
+        r.push_copy("\u{e9}b");
+        // a: 0, \u{2603}: 1..4, \u{e9}: 4..6, b: 6
+
+        assert_eq!(Some(('a', 0)), r.char_at_or_before(0));
+        assert_eq!(Some(('\u{2603}', 1)), r.char_at_or_before(1));
+        assert_eq!(Some(('\u{2603}', 1)), r.char_at_or_before(2));
+        assert_eq!(Some(('\u{2603}', 1)), r.char_at_or_before(3));
+        assert_eq!(Some(('\u{e9}', 4)), r.char_at_or_before(5));
+        assert_eq!(Some(('b', 6)), r.char_at_or_before(6));
+        assert_eq!(None, r.char_at_or_before(7));
+        assert_eq!(None, Rope::new().char_at_or_before(0));
+    }
+
+    #[test]
+    fn test_append_bytes() {
+        let text = "h\u{e9}llo \u{2603}\u{1f600}!";
+        let bytes = text.as_bytes();
+
+        // Split the text at every possible point.
+        for i in 0..bytes.len() + 1 {
+            let mut r = Rope::new();
+            r.append_bytes(&bytes[..i]).unwrap();
+            r.append_bytes(&bytes[i..]).unwrap();
+            r.finish_append().unwrap();
+            assert!(r.to_string() == text);
+        }
+
+        // One byte at a time.
+        let mut r = Rope::new();
+        for b in bytes {
+            r.append_bytes(&[*b]).unwrap();
+        }
+        r.finish_append().unwrap();
+        assert!(r.to_string() == text);
+    }
+
+    #[test]
+    fn test_append_bytes_invalid() {
+        let mut r = Rope::new();
+        r.append_bytes(b"abc\xe2\x98").unwrap();
+        assert!(r.to_string() == "abc");
+        assert!(r.append_bytes(b"\xff").is_err());
+        assert!(r.to_string() == "abc");
+        r.append_bytes(b"\x83!").unwrap();
+        assert!(r.to_string() == "abc\u{2603}!");
+
+        r.append_bytes(b"\xf0\x9f").unwrap();
+        assert!(r.finish_append().is_err());
+        assert!(r.to_string() == "abc\u{2603}!");
+        r.finish_append().unwrap();
+    }
+
+    #[test]
+    fn test_chars_rev_in() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.insert_copy(0, "h\u{e9}llo ");
+        r.push_copy("\u{2603} w\u{f6}rld");
+
+        for &(start, end) in &[(1, 12), (3, 10), (0, r.len()), (5, 5), (10, 14)] {
+            let mut expected: Vec<_> = r.chars().filter(|&(_, b)| b >= start && b < end).collect();
+            expected.reverse();
+            let actual: Vec<_> = r.chars_rev_in(start..end).collect();
+            assert!(actual == expected);
+        }
+    }
+
+    #[test]
+    fn test_common_prefix_with() {
+        let mut r: Rope = "print".parse().unwrap();
+        r.push_copy("ln!(\"\u{e9}\")");
+
+        assert_eq!(5, r.common_prefix_with("printf"));
+        assert_eq!(7, r.common_prefix_with("println"));
+        assert_eq!(0, r.common_prefix_with("eprintln"));
+        assert_eq!(0, r.common_prefix_with(""));
+        // `s` is a prefix of the rope.
+        assert_eq!(9, r.common_prefix_with("println!("));
+        assert_eq!(r.len(), r.common_prefix_with(&r.to_string()));
+        // Don't split a char which shares its first byte.
+        assert_eq!(10, r.common_prefix_with("println!(\"\u{e8}\")"));
+        assert_eq!(0, Rope::new().common_prefix_with("foo"));
+    }
+
+    #[test]
+    fn test_map_lines() {
+        let mut r = Rope::from_lines(["one", "two", "three", "f\u{f6}ur", ""]);
+        r.push_copy("five");
+
+        let mut seen = vec![];
+        r.map_lines(|i, line| {
+            seen.push(line.to_owned());
+            if i % 2 == 0 {
+                Some(line.to_uppercase())
+            } else {
+                None
+            }
+        });
+        assert_eq!(seen, ["one", "two", "three", "f\u{f6}ur", "five"]);
+        assert!(r.to_string() == "ONE\ntwo\nTHREE\nf\u{f6}ur\nFIVE");
+
+        // Replacements may change the length of lines.
+        r.map_lines(|i, _| if i == 1 { Some("a\nb".to_owned()) } else { Some(String::new()) });
+        assert!(r.to_string() == "\na\nb\n\n\n");
+        assert_eq!(6, r.len_lines());
+    }
+
+    #[test]
+    fn test_char_offsets() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{20ac}b\u{20ac}\u{20ac}cd\u{20ac}");
+        assert!(count_leaves(&r) > 1);
+
+        let offsets: Vec<usize> = r.char_offsets('\u{20ac}').collect();
+        assert_eq!(offsets, [1, 5, 8, 13]);
+        for &i in &offsets {
+            assert_eq!(Some(('\u{20ac}', i)), r.char_at_or_before(i));
+        }
+
+        assert_eq!(r.char_offsets('c').collect::<Vec<_>>(), [11]);
+        assert_eq!(0, r.char_offsets('z').count());
+        assert_eq!(0, Rope::new().char_offsets('a').count());
+
+        // Offsets after a char split between leaves.
+        let r = split_char_rope();
+        assert_eq!(r.char_offsets('\u{20ac}').collect::<Vec<_>>(), [1]);
+        assert_eq!(r.char_offsets('b').collect::<Vec<_>>(), [4]);
+        let r = rope_with_leaves_of("a\u{20ac}b\u{20ac}\u{20ac}cd\u{20ac}", 2);
+        assert_eq!(r.char_offsets('\u{20ac}').collect::<Vec<_>>(), [1, 5, 8, 13]);
+    }
+
+    #[test]
+    fn test_storage_policy() {
+        for &policy in &[StoragePolicy::PerInsert, StoragePolicy::Arena(16), StoragePolicy::Interned] {
+            let mut r = Rope::with_policy(policy);
+            let mut s = String::new();
+            for word in &["foo ", "bar ", "foo ", "\u{e9}t\u{e9} ", "foo ", "a long word, longer than the arena "] {
+                r.push_copy(word);
+                s.push_str(word);
+            }
+            r.insert_copy(4, "baz ");
+            s.insert_str(4, "baz ");
+            assert!(r.to_string() == s, "{:?}", policy);
+
+            // Edits in place must only affect the edited text.
+            r.replace(0, 'g');
+            s.replace_range(0..1, "g");
+            assert!(r.to_string() == s, "{:?}", policy);
+            r.make_ascii_uppercase();
+            s.make_ascii_uppercase();
+            assert!(r.to_string() == s, "{:?}", policy);
+
+            r.remove(8, 16);
+            s.replace_range(8..16, "");
+            assert!(r.to_string() == s, "{:?}", policy);
+            r.assert_pointers_valid();
+
+            let tail = r.split_off(10);
+            assert_eq!(policy, tail.policy);
+            assert!(tail.to_string() == s[10..], "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_storage_policy_buffers() {
+        let mut per_insert = Rope::new();
+        let mut arena = Rope::with_policy(StoragePolicy::Arena(64));
+        let mut interned = Rope::with_policy(StoragePolicy::Interned);
+        for _ in 0..32 {
+            per_insert.push_copy("abcd");
+            arena.push_copy("abcd");
+            interned.push_copy("abcd");
+        }
+        assert!(per_insert.to_string() == arena.to_string());
+        assert!(per_insert.to_string() == interned.to_string());
+
+        assert_eq!(32, per_insert.storage.len());
+        assert_eq!(2, arena.storage.len());
+        assert_eq!(1, interned.storage.len());
+
+        // Text which doesn't fit in the arena gets its own buffer.
+        arena.push_copy(&"x".repeat(100));
+        assert_eq!(3, arena.storage.len());
+        arena.push_copy("y");
+        assert_eq!(4, arena.storage.len());
+    }
+
+    #[test]
+    fn test_assert_pointers_valid() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("Hello w\u{f6}rld!");
+        r.insert_copy(5, ",");
+        r.remove(1, 3);
+        r.assert_pointers_valid();
+        Rope::new().assert_pointers_valid();
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the rope's storage")]
+    fn test_assert_pointers_valid_dangling() {
+        let mut r: Rope = "Hello".parse().unwrap();
+        // Replace the storage without rebasing the leaves.
+        r.storage = r.storage.clone();
+        r.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_count_bytes() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("fn main() {\n\tlet x = 1;\n}\n");
+        r.insert_copy(3, "m\u{e9}  ");
+        let s = r.to_string();
+
+        let ws = |b: u8| b.is_ascii_whitespace();
+        assert_eq!(s.bytes().filter(|&b| ws(b)).count(), r.count_bytes(ws));
+        assert_eq!(11, r.count_bytes(ws));
+        assert_eq!(1, r.count_bytes(|b| b == b'\t'));
+        assert_eq!(2, r.count_bytes(|b| !b.is_ascii()));
+        assert_eq!(0, Rope::new().count_bytes(|_| true));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let mut r = Rope::new_with_segment_size(16);
+        r.push_copy("Hello");
+        r.push_copy(" w\u{f6}rld");
+        r.insert_copy(5, ",");
+        r.remove(0, 1);
+        let text = r.to_string();
+        assert!(node_count(&r.root) > 1);
+
+        r.flatten();
+        assert_eq!(1, node_count(&r.root));
+        assert_eq!(1, r.storage.len());
+        assert!(r.to_string() == text);
+        assert_eq!(text.len(), r.len());
+        r.assert_pointers_valid();
+
+        // The rope can still be edited.
+        r.push_copy("!");
+        r.insert_copy(0, "H");
+        assert!(r.to_string() == "Hello, w\u{f6}rld!");
+
+        // Ropes longer than a segment are left alone.
+        r.push_copy(" more text");
+        let nodes = node_count(&r.root);
+        r.flatten();
+        assert_eq!(nodes, node_count(&r.root));
+        assert!(nodes > 1);
+
+        let mut r = Rope::new();
+        r.flatten();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_column_slices() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("ABCd\u{e9}f  g12\nXYZ");
+
+        let fields: Vec<String> = r.column_slices(&[3, 5, 2]).iter().map(|s| s.to_string()).collect();
+        assert_eq!(fields, ["ABC", "d\u{e9}f ", " g"]);
+
+        // The line runs out part way through the third field.
+        let fields: Vec<String> = r.column_slices(&[3, 6, 5, 2]).iter().map(|s| s.to_string()).collect();
+        assert_eq!(fields, ["ABC", "d\u{e9}f  ", "g12"]);
+
+        assert!(r.column_slices(&[]).is_empty());
+        assert!(Rope::new().column_slices(&[3, 5, 2]).is_empty());
+
+        // A width which ends inside a char leaves the char for the next field.
+        let fields: Vec<String> = r.column_slices(&[4, 3, 2]).iter().map(|s| s.to_string()).collect();
+        assert_eq!(fields, ["ABCd", "\u{e9}f", "  "]);
+        let fields: Vec<String> = r.column_slices(&[4, 1, 3]).iter().map(|s| s.to_string()).collect();
+        assert_eq!(fields, ["ABCd", "", "\u{e9}f"]);
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("l\u{ef}ne one\nl\u{ef}ne tw\u{f6}\n");
+
+        assert_eq!(Ok(23), r.insert_at(1, 5, "number "));
+        assert!(r.to_string() == "l\u{ef}ne one\nl\u{ef}ne number tw\u{f6}\n");
+        // At the end of a line.
+        assert_eq!(Ok(28), r.insert_at(1, 15, "!"));
+        assert!(r.to_string() == "l\u{ef}ne one\nl\u{ef}ne number tw\u{f6}!\n");
+        // On the empty last line.
+        assert_eq!(Ok(32), r.insert_at(2, 0, "\u{20ac}"));
+        assert!(r.to_string() == "l\u{ef}ne one\nl\u{ef}ne number tw\u{f6}!\n\u{20ac}");
+        assert_eq!(Ok(1), r.insert_at(0, 0, ">"));
+
+        let text = r.to_string();
+        assert_eq!(Err(RopeError::InvalidPosition { line: 0, col: 10 }), r.insert_at(0, 10, "x"));
+        assert_eq!(Err(RopeError::InvalidPosition { line: 3, col: 0 }), r.insert_at(3, 0, "x"));
+        assert!(r.to_string() == text);
+    }
+
+    #[test]
+    fn test_trim_blank_lines() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("\n  \n\t\nfirst\n\n  \nl\u{e4}st  \n \n\n");
+        r.trim_blank_lines();
+        assert!(r.to_string() == "first\n\n  \nl\u{e4}st  \n");
+
+        let mut r: Rope = "\n\n  indented\nno newline\n\t".parse().unwrap();
+        r.trim_blank_lines();
+        assert!(r.to_string() == "  indented\nno newline\n");
+
+        let mut r: Rope = "no blank lines".parse().unwrap();
+        r.trim_blank_lines();
+        assert!(r.to_string() == "no blank lines");
+
+        let mut r: Rope = " \n\u{3000}\n\n".parse().unwrap();
+        r.trim_blank_lines();
+        assert!(r.to_string() == "");
+        r.trim_blank_lines();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_utf16_col() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("first\nab\u{1f600}c\u{e9}d\n\u{1f600}");
+
+        assert_eq!(0, r.utf16_col(0));
+        assert_eq!(5, r.utf16_col(5));
+        assert_eq!(0, r.utf16_col(6));
+        assert_eq!(2, r.utf16_col(8));
+        // The emoji is one char but two UTF-16 code units.
+        assert_eq!(4, r.utf16_col(12));
+        assert_eq!(3, r.byte_to_char(12) - r.line_to_char(1));
+        assert_eq!(5, r.utf16_col(13));
+        assert_eq!(6, r.utf16_col(15));
+        assert_eq!(7, r.utf16_col(16));
+        assert_eq!(0, r.utf16_col(17));
+        assert_eq!(2, r.utf16_col(r.len()));
+    }
+
+    #[test]
+    fn test_chars_seek() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("fn f\u{f6}\u{f6}(x: \u{20ac}) {}");
+        let expected: Vec<(char, usize)> = r.chars().collect();
+
+        let mut chars = r.chars();
+        for _ in 0..5 {
+            chars.next();
+        }
+        let saved = chars.position();
+        let ahead: Vec<(char, usize)> = chars.by_ref().take(6).collect();
+        assert_eq!(&expected[5..11], &ahead[..]);
+
+        chars.seek_to(saved);
+        assert_eq!(saved, chars.position());
+        let rest: Vec<(char, usize)> = chars.collect();
+        assert_eq!(&expected[5..], &rest[..]);
+
+        // Seeking to the end.
+        let mut chars = r.chars();
+        let start = chars.position();
+        while chars.next().is_some() {}
+        let end = chars.position();
+        chars.seek_to(start);
+        assert_eq!(Some(('f', 0)), chars.next());
+        chars.seek_to(end);
+        assert_eq!(None, chars.next());
+    }
+
+    #[cfg(feature = "grapheme")]
+    #[test]
+    fn test_grapheme_indices() {
+        // The flag is two regional indicator chars of four bytes each, which
+        // are split between leaves.
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{1f1ec}\u{1f1e7}e\u{301}!");
+        assert_eq!(2, r.segments_in(1..9).count());
+
+        let graphemes: Vec<(usize, Cow<str>)> = r.grapheme_indices().collect();
+        assert_eq!(graphemes, [(0, Cow::Borrowed("a")),
+                               (1, Cow::Borrowed("\u{1f1ec}\u{1f1e7}")),
+                               (9, Cow::Borrowed("e\u{301}")),
+                               (12, Cow::Borrowed("!"))]);
+        // Only the flag crosses a leaf boundary.
+        let owned: Vec<bool> = graphemes.iter().map(|(_, g)| matches!(g, Cow::Owned(_))).collect();
+        assert_eq!(owned, [false, true, false, false]);
+        assert_eq!(0, Rope::new().grapheme_indices().count());
+    }
+
+    #[cfg(feature = "grapheme")]
+    #[test]
+    fn test_grapheme_indices_split_chars() {
+        use unicode_segmentation::UnicodeSegmentation;
+
+        // Flags, combining marks, CRLF and a ZWJ sequence.
+        let text = concat!("a\u{1f1ec}\u{1f1e7}\u{1f1fa}\u{1f1f8}e\u{301}\u{301}\r\n",
+                           "\u{1f469}\u{200d}\u{1f52c} \u{e9}x");
+        for size in 1..8 {
+            let r = rope_with_leaves_of(text, size);
+            assert!(r.has_split_chars());
+
+            let graphemes: Vec<(usize, String)> = r.grapheme_indices()
+                                                   .map(|(i, g)| (i, g.into_owned()))
+                                                   .collect();
+            let expected: Vec<(usize, String)> = text.grapheme_indices(true)
+                                                     .map(|(i, g)| (i, g.to_owned()))
+                                                     .collect();
+            assert_eq!(graphemes, expected, "segment size {}", size);
+        }
+    }
+
+    #[cfg(feature = "width")]
+    #[test]
+    fn test_display_width() {
+        let mut r: Rope = "ab\u{4e2d}".parse().unwrap();
+        r.push_copy("e\u{301}\tx\nsecond line");
+
+        // "ab" 2, wide char 2, "e" and a combining mark 1, tab to 8, "x" 1.
+        assert_eq!(9, r.display_width(8));
+        assert_eq!(7, r.display_width(2));
+        assert_eq!(0, Rope::new().display_width(4));
+        assert_eq!(4, "\tx".parse::<Rope>().unwrap().display_width(3));
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        fn hunk(start_byte: usize, removed_len: usize, insert_text: &str) -> Hunk {
+            Hunk {
+                start_byte,
+                removed_len,
+                insert_text: insert_text.to_owned(),
+            }
+        }
+
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("let x = f\u{f6}\u{f6}(1);\nlet y = 2;\n");
+
+        // Out of order, and including a pure insertion and a pure deletion.
+        r.apply_patch(&[hunk(22, 1, "z"), hunk(0, 0, "// hi\n"), hunk(8, 5, "bar"), hunk(15, 1, "")])
+         .unwrap();
+        assert!(r.to_string() == "// hi\nlet x = bar(1;\nlet z = 2;\n");
+        r.apply_patch(&[]).unwrap();
+
+        let text = r.to_string();
+        assert_eq!(Err(PatchError::Overlap(1, 0)), r.apply_patch(&[hunk(4, 3, "a"), hunk(2, 3, "b")]));
+        assert_eq!(Err(PatchError::Overlap(0, 1)), r.apply_patch(&[hunk(4, 0, "a"), hunk(4, 2, "b")]));
+        assert_eq!(Err(PatchError::OutOfBounds(1)), r.apply_patch(&[hunk(0, 1, ""), hunk(30, 5, "")]));
+        assert_eq!(Err(PatchError::OutOfBounds(0)), r.apply_patch(&[hunk(1, usize::MAX, "")]));
+        // Adjacent hunks are fine.
+        r.apply_patch(&[hunk(0, 3, "/*"), hunk(3, 2, " */")]).unwrap();
+        assert!(r.to_string() == format!("/* */{}", &text[5..]));
+    }
+
+    #[test]
+    fn test_apply_patch_char_boundary() {
+        let mut r: Rope = "f\u{f6}\u{f6}".parse().unwrap();
+        assert_eq!(Err(PatchError::OutOfBounds(0)), r.apply_patch(&[Hunk {
+            start_byte: 1,
+            removed_len: 1,
+            insert_text: "o".to_owned(),
+        }]));
+        assert!(r.to_string() == "f\u{f6}\u{f6}");
+    }
+
+    #[test]
+    fn test_has_split_chars() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("a\u{20ac}b\u{20ac}\u{f6}");
+        r.insert_copy(4, "\u{20ac}");
+        r.remove(1, 4);
+        assert!(!r.has_split_chars());
+        assert!(!Rope::new().has_split_chars());
+
+        // Removing the first byte of a three byte char leaves the rest of it
+        // at the start of a leaf.
+        let mut r: Rope = "a\u{20ac}b".parse().unwrap();
+        r.remove(1, 2);
+        assert!(r.has_split_chars());
+    }
+
+    #[test]
+    fn test_heal_boundaries() {
+        // Removing the last two bytes of one char and the first byte of the
+        // next leaves a valid char split between two leaves.
+        let mut r: Rope = "a\u{20ac}\u{20ac}b".parse().unwrap();
+        r.remove(2, 5);
+        assert!(r.has_split_chars());
+        r.heal_boundaries();
+        assert!(!r.has_split_chars());
+        assert!(r.to_string() == "a\u{20ac}b");
+        r.assert_pointers_valid();
+        assert_eq!(1, r.segments_in(0..r.len()).filter(|&(s, _)| s.contains('\u{20ac}')).count());
+
+        // A char split across three leaves. Other leaves are left alone.
+        let mut r: Rope = "a\u{1f600}\u{1f601}\u{1f602}b".parse().unwrap();
+        r.remove(2, 6);
+        r.remove(3, 7);
+        r.push_copy("!");
+        assert_eq!(4, count_leaves(&r));
+        r.heal_boundaries();
+        assert!(!r.has_split_chars());
+        assert!(r.to_string() == "a\u{1f602}b!");
+        let segments: Vec<&str> = r.segments_in(0..r.len()).map(|(s, _)| s).collect();
+        assert_eq!(segments, ["a\u{1f602}b", "!"]);
+
+        let mut r = Rope::new();
+        r.heal_boundaries();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_sort_lines() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("pear\napple\n\u{e9}clair\nBanana\n\napple\n");
+        r.sort_lines();
+        assert!(r.to_string() == "\nBanana\napple\napple\npear\n\u{e9}clair\n");
+        // The old storage is freed.
+        assert_eq!(r.storage.len(), 1);
+
+        // Without a trailing newline.
+        let mut r: Rope = "b\nc\na".parse().unwrap();
+        r.sort_lines();
+        assert!(r.to_string() == "a\nb\nc");
+
+        let mut r: Rope = "\n".parse().unwrap();
+        r.sort_lines();
+        assert!(r.to_string() == "\n");
+        let mut r = Rope::new();
+        r.sort_lines();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_dedup_adjacent_lines() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\na\na\nb\n\u{e9}\n\u{e9}\na\n\n\n\nb\nb");
+        r.dedup_adjacent_lines();
+        assert!(r.to_string() == "a\nb\n\u{e9}\na\n\nb");
+
+        // The trailing newline is kept.
+        let mut r: Rope = "x\nx\n\n\n".parse().unwrap();
+        r.dedup_adjacent_lines();
+        assert!(r.to_string() == "x\n\n");
+
+        let mut r: Rope = "a\nb\na\nb\n".parse().unwrap();
+        r.dedup_adjacent_lines();
+        assert!(r.to_string() == "a\nb\na\nb\n");
+
+        let mut r = Rope::new();
+        r.dedup_adjacent_lines();
+        assert!(r.to_string() == "");
+    }
+
+    #[test]
+    fn test_join() {
+        let r = Rope::join(["a", "b", "c"], ", ");
+        assert!(r.to_string() == "a, b, c");
+        assert_eq!(7, r.len());
+        assert_eq!(1, r.storage.len());
+
+        let parts = vec!["\u{e9}".to_owned(), String::new(), "x".to_owned()];
+        assert!(Rope::join(&parts, "--").to_string() == "\u{e9}----x");
+        assert!(Rope::join(["only"], ", ").to_string() == "only");
+        assert!(Rope::join(Vec::<&str>::new(), ", ").to_string() == "");
+        assert!(Rope::join(["a", "b"], "").to_string() == "ab");
+    }
+
+    #[test]
+    fn test_len_deep_tree() {
+        // Build a right-leaning rope with one inner node per char, the shape
+        // produced by many small edits at the end of an unbalanced rope.
+        let text = "x".repeat(200_000);
+        let mut root = Node::new_leaf(text.as_ptr(), 1);
+        for i in 1..text.len() {
+            let leaf = Node::new_leaf(&text.as_bytes()[i] as *const u8, 1);
+            root = Node::new_inner(Some(Box::new(leaf)), Some(Box::new(root)), 1);
+        }
+        assert_eq!(text.len(), root.len());
+
+        let mut r = Rope::new();
+        r.root = root;
+        r.len = text.len();
+        assert_eq!(text.len(), r.len());
+
+        // Dropping the tree recurses too.
+        ::std::mem::forget(r);
+    }
+
+    #[test]
+    fn test_slice_deep_tree() {
+        let mut r = Rope::new();
+        let mut s = String::new();
+        for i in 0..5000 {
+            let c = if i % 7 == 0 { '\u{e9}' } else { (b'a' + (i % 26) as u8) as char };
+            r.push_copy(c.encode_utf8(&mut [0; 4]));
+            s.push(c);
+        }
+        // Each push adds a level to the right spine of the tree.
+        assert!(depth(&r) >= 5000);
+
+        assert!(r.slice(0..r.len()).to_string() == s);
+        assert!(r.slice(1000..1500).to_string() == s[1000..1500]);
+        assert!(r.to_string() == s);
+    }
+
+    #[test]
+    fn test_ascii_case_deep_tree() {
+        // A chain of 100 000 inner nodes, built directly since pushing that
+        // many leaves takes quadratic time.
+        let buf = vec![b'x'; 100_000];
+        let mut root = Node::new_leaf(buf.as_ptr(), 1);
+        for i in 1..buf.len() {
+            let leaf = Node::new_leaf(buf[i..].as_ptr(), 1);
+            root = Node::new_inner(Some(Box::new(root)), Some(Box::new(leaf)), i);
+        }
+        let mut r = Rope::new();
+        r.root = root;
+        r.storage.push(buf);
+        r.len = 100_000;
+
+        r.make_ascii_uppercase();
+        assert!(r.to_string() == "X".repeat(100_000));
+        r.make_ascii_lowercase();
+        assert!(r.to_string() == "x".repeat(100_000));
+    }
+
+    #[test]
+    fn test_chars_until() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("na\u{ef}ve\u{e9}\u{e9} token");
+        assert!(count_leaves(&r) > 2);
+
+        assert_eq!(7, r.chars_until(char::is_whitespace));
+        assert_eq!(2, r.chars_until(|c| !c.is_ascii()));
+        assert_eq!(0, r.chars_until(|_| true));
+        assert_eq!(r.len_chars(), r.chars_until(|c| c == '!'));
+        assert_eq!(0, Rope::new().chars_until(|_| false));
+
+        let r = split_char_rope();
+        assert_eq!(2, r.chars_until(|c| c == 'b'));
+        assert_eq!(3, r.chars_until(|_| false));
+        let r = rope_with_leaves_of("x\u{1f600}\u{e9} y", 1);
+        assert_eq!(3, r.chars_until(char::is_whitespace));
+    }
+
+    #[test]
+    fn test_slice_from_to() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("Hello, w\u{f6}rld!");
+        let len = r.len();
+
+        for &i in &[0, 1, 5, 8, 10, len] {
+            assert!(r.slice_from(i).to_string() == r.slice(i..len).to_string());
+            assert!(r.slice_to(i).to_string() == r.slice(0..i).to_string());
+        }
+        assert!(r.slice_from(7).to_string() == "w\u{f6}rld!");
+        assert!(r.slice_to(5).to_string() == "Hello");
+        assert!(Rope::new().slice_from(0).to_string() == "");
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_slice_from_out_of_bounds() {
+        let r: Rope = "abc".parse().unwrap();
+        r.slice_from(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_slice_to_out_of_bounds() {
+        let r: Rope = "abc".parse().unwrap();
+        r.slice_to(4);
+    }
+
+    #[test]
+    fn test_boundaries_in() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{e9}\u{20ac}b\u{1f600}c\u{f6}");
+        let text = r.to_string();
+
+        for &(start, end) in &[(0, r.len()), (1, 9), (2, 12), (4, 5), (6, 6)] {
+            let expected: Vec<usize> = text.char_indices()
+                                           .map(|(i, _)| i)
+                                           .filter(|&i| i >= start && i < end)
+                                           .collect();
+            let found: Vec<usize> = r.boundaries_in(start..end).collect();
+            assert_eq!(expected, found, "{}..{}", start, end);
+        }
+        assert_eq!(r.boundaries_in(0..r.len()).collect::<Vec<_>>(), [0, 1, 3, 6, 7, 11, 12]);
+    }
+
+    #[test]
+    fn test_len_bytes() {
+        let mut r: Rope = "gr\u{fc}\u{df}e ".parse().unwrap();
+        r.push_copy("\u{1f600}");
+        assert_eq!(r.to_string().len(), r.len_bytes());
+        assert_eq!(r.len(), r.len_bytes());
+        assert_eq!(12, r.len_bytes());
+        assert_eq!(7, r.len_chars());
+        assert_eq!(0, Rope::new().len_bytes());
+    }
+
+    #[test]
+    fn test_is_char_boundary() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("a\u{e9}\u{20ac}b");
+        let text = r.to_string();
+        for i in 0..text.len() + 2 {
+            assert_eq!(text.is_char_boundary(i), r.is_char_boundary(i), "{}", i);
+        }
+        assert!(r.is_char_boundary(0));
+        assert!(r.is_char_boundary(r.len()));
+        assert!(!r.is_char_boundary(2));
+        assert!(!r.is_char_boundary(r.len() + 1));
+        assert!(Rope::new().is_char_boundary(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "byte 2 is not a char boundary of rope (len 4)")]
+    fn test_insert_not_char_boundary() {
+        let mut r: Rope = "a\u{e9}b".parse().unwrap();
+        r.insert_copy(2, "x");
+    }
+
+    #[test]
+    #[should_panic(expected = "byte 5 out of bounds of rope (len 4)")]
+    fn test_insert_out_of_bounds() {
+        let mut r: Rope = "a\u{e9}b".parse().unwrap();
+        r.insert_copy(5, "x");
+    }
+
+    #[test]
+    #[should_panic(expected = "range 2..6 out of bounds of rope (len 4)")]
+    fn test_remove_out_of_bounds() {
+        let mut r: Rope = "a\u{e9}b".parse().unwrap();
+        r.remove(2, 6);
+    }
+
+    #[test]
+    fn test_clamp_to_boundary() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("a\u{e9}\u{1f600}b\u{20ac}");
+        let text = r.to_string();
+
+        let expected = [0, 1, 1, 3, 3, 3, 3, 7, 8, 8, 8, 11, 11, 11];
+        for (i, &e) in expected.iter().enumerate() {
+            assert_eq!(e, r.clamp_to_boundary(i), "{}", i);
+            assert!(text.is_char_boundary(e));
+        }
+        assert_eq!(11, r.len());
+        assert_eq!(0, Rope::new().clamp_to_boundary(5));
+    }
+
+    #[test]
+    fn test_byte_chunks_exact() {
+        let mut r = Rope::new_with_segment_size(5);
+        r.push_copy("The quick brown f\u{f6}x jumps over the lazy dog");
+        let bytes = r.to_string().into_bytes();
+
+        for &size in &[1, 3, 4, 7, 16, 100] {
+            let chunks: Vec<Cow<[u8]>> = r.byte_chunks_exact(size).collect();
+            assert_eq!(bytes.len().div_ceil(size), chunks.len());
+            let (last, rest) = chunks.split_last().unwrap();
+            assert!(rest.iter().all(|c| c.len() == size), "{}", size);
+            assert!(!last.is_empty() && last.len() <= size);
+            assert_eq!(bytes, chunks.concat(), "{}", size);
+        }
+
+        // Chunks within a leaf are not copied.
+        let chunks: Vec<Cow<[u8]>> = r.byte_chunks_exact(5).collect();
+        assert!(chunks.iter().all(|c| matches!(*c, Cow::Borrowed(_))));
+        let chunks: Vec<Cow<[u8]>> = r.byte_chunks_exact(4).collect();
+        assert!(chunks.iter().any(|c| matches!(*c, Cow::Owned(_))));
+
+        assert_eq!(0, Rope::new().byte_chunks_exact(4).count());
+    }
+
+    #[test]
+    fn test_first_last_line() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("title: r\u{f6}pe\nbody\nstatus: ok");
+        assert!(r.first_line().to_string() == "title: r\u{f6}pe");
+        assert!(r.last_line().to_string() == "status: ok");
+
+        r.push_copy("\n");
+        assert!(r.first_line().to_string() == "title: r\u{f6}pe");
+        assert!(r.last_line().to_string() == "status: ok");
+        r.push_copy("\n");
+        assert!(r.last_line().to_string() == "");
+
+        let r: Rope = "one line".parse().unwrap();
+        assert!(r.first_line().to_string() == "one line");
+        assert!(r.last_line().to_string() == "one line");
+        let r: Rope = "one line\n".parse().unwrap();
+        assert!(r.last_line().to_string() == "one line");
+
+        let r = Rope::new();
+        assert!(r.first_line().to_string() == "");
+        assert!(r.last_line().to_string() == "");
+        let r: Rope = "\n".parse().unwrap();
+        assert!(r.first_line().to_string() == "");
+        assert!(r.last_line().to_string() == "");
+    }
+
+    #[test]
+    fn test_find() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("one t\u{e9}o three t\u{e9}o");
+        assert_eq!(Some(0), r.find("one"));
+        assert_eq!(Some(4), r.find("t\u{e9}o"));
+        assert_eq!(Some(9), r.find("three"));
+        assert_eq!(Some(0), r.find(""));
+        assert_eq!(None, r.find("four"));
+        assert_eq!(None, r.find("t\u{e9}o t\u{e9}o t\u{e9}o"));
+        assert_eq!(None, Rope::new().find("a"));
+    }
+
+    #[test]
+    fn test_replace_first() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("foo(x); foo(y); foo(z);");
+        assert!(r.replace_first("foo", "b\u{e4}rbaz"));
+        assert!(r.to_string() == "b\u{e4}rbaz(x); foo(y); foo(z);");
+        assert!(r.replace_first("foo", "q"));
+        assert!(r.to_string() == "b\u{e4}rbaz(x); q(y); foo(z);");
+        assert!(r.replace_first("(z)", ""));
+        assert!(r.to_string() == "b\u{e4}rbaz(x); q(y); foo;");
+
+        assert!(!r.replace_first("nope", "x"));
+        assert!(r.to_string() == "b\u{e4}rbaz(x); q(y); foo;");
+    }
+
+    #[test]
+    fn test_char_at_utf16() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{1f600}\u{e9}\n\u{1f601}b");
+
+        assert_eq!(Some('a'), r.char_at_utf16(0));
+        assert_eq!(Some('\u{1f600}'), r.char_at_utf16(1));
+        // Inside the surrogate pair.
+        assert_eq!(None, r.char_at_utf16(2));
+        assert_eq!(Some('\u{e9}'), r.char_at_utf16(3));
+        assert_eq!(Some('\n'), r.char_at_utf16(4));
+        assert_eq!(Some('\u{1f601}'), r.char_at_utf16(5));
+        assert_eq!(None, r.char_at_utf16(6));
+        assert_eq!(Some('b'), r.char_at_utf16(7));
+        assert_eq!(None, r.char_at_utf16(8));
+        assert_eq!(None, Rope::new().char_at_utf16(0));
+
+        let r = split_char_rope();
+        assert_eq!(Some('\u{20ac}'), r.char_at_utf16(1));
+        assert_eq!(Some('b'), r.char_at_utf16(2));
+        let r = rope_with_leaves_of("a\u{1f600}\u{e9}\n\u{1f601}b", 3);
+        assert_eq!(Some('\u{1f601}'), r.char_at_utf16(5));
+        assert_eq!(Some('b'), r.char_at_utf16(7));
+    }
+
+    #[test]
+    fn test_retain_lines() {
+        fn retain_non_blank(s: &str) -> String {
+            s.split_inclusive('\n').filter(|l| !l.trim().is_empty()).collect()
+        }
+
+        for text in &["\n \na\n\n\tb \u{e9}\n  \n\nc\n\n", "a\n\nb", "a\n\n ", "\n\n", "abc", ""] {
+            let mut r = Rope::new_with_segment_size(3);
+            r.push_copy(text);
+            r.retain_lines(|_, line| !line.trim().is_empty());
+            assert!(r.to_string() == retain_non_blank(text), "{:?}", text);
+        }
+
+        let mut r: Rope = "zero\none\ntwo\nthree".parse().unwrap();
+        let mut seen = vec![];
+        r.retain_lines(|i, line| {
+            seen.push(line.to_owned());
+            i % 2 == 1
+        });
+        assert_eq!(seen, ["zero", "one", "two", "three"]);
+        assert!(r.to_string() == "one\nthree");
+    }
+
+    #[test]
+    fn test_prepend() {
+        let mut r = Rope::new();
+        let mut s = String::new();
+        for i in 0..5000 {
+            let text = format!("{}\u{e9} ", i);
+            r.prepend(&text);
+            s.insert_str(0, &text);
+        }
+        assert!(r.to_string() == s);
+        assert_eq!(s.len(), r.len());
+        r.assert_pointers_valid();
+        // A new level is only added when a buffer fills.
+        assert!(depth(&r) <= 20, "depth {}", depth(&r));
+
+        // Other edits at the front of the rope.
+        r.remove(0, 3);
+        s.replace_range(0..3, "");
+        r.prepend("a");
+        s.insert(0, 'a');
+        r.insert_copy(0, "b");
+        s.insert(0, 'b');
+        r.prepend("c");
+        s.insert(0, 'c');
+        r.insert_copy(2, "d");
+        s.insert(2, 'd');
+        r.prepend("e");
+        s.insert(0, 'e');
+        r.push_copy("f");
+        s.push('f');
+        assert!(r.to_string() == s);
+        r.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_prepend_segment_size() {
+        let mut r = Rope::new_with_segment_size(8);
+        r.push_copy("tail");
+        for _ in 0..10 {
+            r.prepend("\u{20ac}");
+        }
+        r.prepend("longer than a segment");
+        assert!(r.to_string() == format!("longer than a segment{}tail", "\u{20ac}".repeat(10)));
+        assert!(r.segments_in(0..r.len()).all(|(s, _)| s.len() <= 8));
+
+        let mut r: Rope = "loaded".parse().unwrap();
+        r.flatten();
+        r.prepend("pre");
+        assert!(r.to_string() == "preloaded");
+    }
+
+    #[test]
+    fn test_char_counts_random_edits() {
+        // A small LCG, so the sequence of edits is reproducible.
+        let mut seed: u64 = 0x2545_f491;
+        let mut next = move |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((seed >> 33) as usize) % n
+        };
+        let pieces = ["a", "bc", "é", "中文", "🦀", "x\ny", "defghij"];
+
+        let mut r = Rope::from_string("start".to_string());
+        let mut s = "start".to_string();
+        for _ in 0..2000 {
+            let boundaries: Vec<usize> = s.char_indices().map(|(i, _)| i).chain(Some(s.len())).collect();
+            let at = boundaries[next(boundaries.len())];
+            let text = pieces[next(pieces.len())];
+            match next(5) {
+                0 => {
+                    r.insert_copy(at, text);
+                    s.insert_str(at, text);
+                }
+                1 => {
+                    let end = boundaries[next(boundaries.len())];
+                    let (start, end) = (::std::cmp::min(at, end), ::std::cmp::max(at, end));
+                    if start < end {
+                        r.remove(start, end);
+                        s.replace_range(start..end, "");
+                    }
+                }
+                2 => {
+                    r.prepend(text);
+                    s.insert_str(0, text);
+                }
+                3 => {
+                    // Overwrite two ASCII chars with one two-byte char.
+                    if s[at..].len() >= 2 && s.as_bytes()[at..at + 2].is_ascii() {
+                        r.replace_str(at, "é");
+                        s.replace_range(at..at + 2, "é");
+                    }
+                }
+                _ => {
+                    let end = ::std::cmp::min(boundaries[next(boundaries.len())], s.len());
+                    let end = ::std::cmp::max(at, end);
+                    r.splice(at..end, text);
+                    s.replace_range(at..end, text);
+                }
+            }
+
+            r.assert_pointers_valid();
+            assert_eq!(check_char_counts(&r.root), s.chars().count());
+            assert_eq!(r.len_chars(), s.chars().count());
+            let byte = boundaries[next(boundaries.len())];
+            if byte <= s.len() && s.is_char_boundary(byte) {
+                let char_idx = s[..byte].chars().count();
+                assert_eq!(r.byte_to_char(byte), char_idx);
+                assert_eq!(r.char_to_byte(char_idx), byte);
+            }
+        }
+        assert_eq!(r.to_string(), s);
+    }
+
+    #[test]
+    fn test_char_counts_split_chars() {
+        // Leaves split inside a char count the char only where it starts.
+        let mut r: Rope = "a\u{20ac}\u{20ac}b".parse().unwrap();
+        r.remove(2, 5);
+        assert!(r.has_split_chars());
+        assert_eq!(check_char_counts(&r.root), 3);
+        assert_eq!(r.len_chars(), 3);
+        assert_eq!(r.char_to_byte(1), 1);
+        assert_eq!(r.char_to_byte(2), 4);
+        assert_eq!(r.byte_to_char(2), 2);
+        assert_eq!(r.byte_to_char(4), 2);
+        assert_eq!(r.char_to_byte(3), r.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "char 4 out of bounds of rope (3 chars)")]
+    fn test_char_to_byte_out_of_bounds() {
+        Rope::from_string("abc".to_string()).char_to_byte(4);
+    }
+
+    #[test]
+    fn test_word_at() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("let foo_bar = café(x);  y");
+
+        // In the middle of a word, and at either end of it.
+        assert_eq!(r.word_at(6), 4..11);
+        assert_eq!(r.word_at(4), 4..11);
+        assert_eq!(r.word_at(11), 4..11);
+        assert_eq!(r.word_at(0), 0..3);
+        assert_eq!(r.word_at(r.len()), r.len() - 1..r.len());
+
+        // Non-ASCII letters are part of words.
+        assert_eq!(r.word_at(15), 14..19);
+        assert_eq!(r.word_at(19), 14..19);
+
+        // Between whitespace or punctuation.
+        assert_eq!(r.word_at(12), 12..12);
+        assert_eq!(r.word_at(24), 24..24);
+        assert_eq!(r.word_at(13), 13..13);
+
+        assert_eq!(Rope::new().word_at(0), 0..0);
+    }
+
+    #[test]
+    #[should_panic(expected = "byte 4 is not a char boundary of rope (len 6)")]
+    fn test_word_at_not_char_boundary() {
+        Rope::from_string("café!".to_string()).word_at(4);
+    }
+
+    #[test]
+    fn test_paragraph_at() {
+        let mut r = Rope::new_with_segment_size(5);
+        r.push_copy("one\ntwo\n\nthree\n  \t\nfour\nfive\n");
+
+        assert_eq!(r.paragraph_at(0), 0..7);
+        assert_eq!(r.paragraph_at(5), 0..7);
+        assert_eq!(r.paragraph_at(7), 0..7);
+        assert_eq!(r.paragraph_at(9), 9..14);
+        assert_eq!(r.paragraph_at(14), 9..14);
+        assert_eq!(r.paragraph_at(25), 19..28);
+
+        // On blank lines, including the empty line after the trailing newline.
+        assert_eq!(r.paragraph_at(8), 8..8);
+        assert_eq!(r.paragraph_at(16), 16..16);
+        assert_eq!(r.paragraph_at(r.len()), r.len()..r.len());
+
+        let r = Rope::from_string("\n\nonly\n".to_string());
+        assert_eq!(r.paragraph_at(4), 2..6);
+        assert_eq!(Rope::new().paragraph_at(0), 0..0);
+
+        // No blank lines, and a paragraph at the very end.
+        let r = Rope::from_string("a\nb\nc".to_string());
+        assert_eq!(r.paragraph_at(2), 0..5);
+        assert_eq!(r.paragraph_at(5), 0..5);
+        let r = Rope::from_string("a\n\nbc".to_string());
+        assert_eq!(r.paragraph_at(3), 3..5);
+        assert_eq!(r.paragraph_at(1), 0..1);
+    }
+
+    #[test]
+    fn test_paragraph_at_many() {
+        let mut r = Rope::new_with_segment_size(64);
+        r.push_copy(&"first line\nsecond line\n\n".repeat(20_000));
+        let start = 19_999 * 24;
+        assert_eq!(r.paragraph_at(start + 14), start..start + 22);
+        assert_eq!(r.paragraph_at(30), 24..46);
+    }
+
+    #[test]
+    fn test_byte_to_char_checked() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("a\u{20ac}b\u{1f600}");
+
+        assert_eq!(r.byte_to_char_checked(0), Some(0));
+        assert_eq!(r.byte_to_char_checked(4), Some(2));
+        assert_eq!(r.byte_to_char_checked(5), Some(3));
+        assert_eq!(r.byte_to_char_checked(r.len()), Some(4));
+
+        assert_eq!(r.byte_to_char_checked(2), None);
+        assert_eq!(r.byte_to_char_checked(7), None);
+        assert_eq!(r.byte_to_char_checked(r.len() + 1), None);
+        assert_eq!(Rope::new().byte_to_char_checked(0), Some(0));
+    }
+
+    #[test]
+    fn test_chunks_rev() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("hello \u{20ac}orld");
+        r.insert_copy(5, ",");
+        r.remove(0, 1);
+        assert!(r.chunks_rev().count() > 1);
+
+        let mut expected = r.to_string();
+        for chunk in r.chunks_rev() {
+            assert!(expected.ends_with(chunk));
+            let len = expected.len() - chunk.len();
+            expected.truncate(len);
+        }
+        assert!(expected.is_empty());
+
+        assert_eq!(Rope::new().chunks_rev().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "leaf boundary splits a char")]
+    fn test_chunks_rev_split_char() {
+        let mut r: Rope = "a\u{20ac}\u{20ac}b".parse().unwrap();
+        r.remove(2, 5);
+        r.chunks_rev().count();
+    }
+
+    #[test]
+    fn test_with_capacity_lines() {
+        let mut r = Rope::with_capacity_lines(100, 11);
+        assert!(r.len() == 0);
+        assert_eq!(r.storage.len(), 1);
+        let buf = r.storage[0].as_ptr();
+
+        let mut expected = String::new();
+        for i in 0..100 {
+            let line = format!("line {:>6}\n", i);
+            r.push_copy(&line);
+            expected.push_str(&line);
+        }
+        assert_eq!(r.to_string(), expected);
+        assert_eq!(r.len_lines(), 101);
+        assert_eq!(r.storage.len(), 1);
+        assert_eq!(r.storage[0].as_ptr(), buf);
+
+        // Text beyond the hint still fits, in a new buffer.
+        r.push_copy("more");
+        assert_eq!(r.storage.len(), 2);
+        r.assert_pointers_valid();
+
+        assert_eq!(Rope::with_capacity_lines(0, 0).to_string(), "");
+    }
+
+    #[test]
+    fn test_with_capacity_lines_auto_compact() {
+        let mut r = Rope::with_capacity_lines(1000, 79);
+        r.set_auto_compact(0.5);
+        r.push_copy(&"line\n".repeat(100));
+        assert_eq!(r.capacity(), 80_000);
+
+        // Compaction sizes storage to the text, not to the hint.
+        r.remove(0, 5);
+        assert_eq!(r.capacity(), 495);
+        assert!(r.policy == StoragePolicy::PerInsert);
+
+        // So the next removal does not compact again.
+        let buf = r.storage[0].as_ptr();
+        r.remove(0, 5);
+        assert_eq!(r.capacity(), 495);
+        assert_eq!(r.storage[0].as_ptr(), buf);
+        assert!(r.to_string() == "line\n".repeat(98));
+    }
+
+    #[test]
+    fn test_as_slices() {
+        use std::io::{IoSlice, Write};
+
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("Hello, \u{20ac}world!\n");
+        r.insert_copy(5, " there");
+        r.remove(0, 1);
+
+        let slices = r.as_slices();
+        assert_eq!(slices.len(), count_leaves(&r));
+        assert_eq!(slices.concat(), r.to_string());
+
+        let bufs: Vec<IoSlice<'_>> = slices.iter().map(|s| IoSlice::new(s.as_bytes())).collect();
+        let mut out = vec![];
+        assert_eq!(out.write_vectored(&bufs).unwrap(), r.len());
+        assert_eq!(out, r.to_string().into_bytes());
+
+        assert!(Rope::new().as_slices().is_empty());
+    }
+
+    #[test]
+    fn test_insert_balanced() {
+        // A small LCG, so the inserts are reproducible.
+        let mut seed: u64 = 7;
+        let mut next = move |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((seed >> 33) as usize) % n
+        };
+
+        let mut r = Rope::new_with_segment_size(4);
+        let mut s = String::new();
+        let mut unbalanced = Rope::new_with_segment_size(4);
+        for i in 0..3000 {
+            let at = next(s.len() + 1);
+            let text = if i % 3 == 0 { "abcdef" } else { "x" };
+            r.insert_balanced(at, text);
+            unbalanced.insert_copy(at, text);
+            s.insert_str(at, text);
+        }
+        assert_eq!(r.to_string(), s);
+        r.assert_pointers_valid();
+        assert_eq!(r.len_chars(), s.len());
+        assert_eq!(check_char_counts(&r.root), s.len());
+
+        // log2(len) is about 13.
+        assert!(depth(&r) <= 2 * 14 + 2, "depth {}", depth(&r));
+        assert!(depth(&r) < depth(&unbalanced));
+
+        // Appending one char at a time is the worst case for a plain insert.
+        let mut r = Rope::new();
+        for _ in 0..2000 {
+            let len = r.len();
+            r.insert_balanced(len, "y");
+        }
+        assert_eq!(r.to_string(), "y".repeat(2000));
+        assert!(depth(&r) <= 2 * 11 + 2, "depth {}", depth(&r));
+    }
+
+    #[test]
+    fn test_utf16_len_in() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{1f600}b\u{e9}\u{1d11e}c");
+
+        let all = 0..r.len();
+        let chars = r.slice(all.clone()).char_indices().count();
+        assert_eq!(chars, 6);
+        // Two astral chars, each of which is a surrogate pair.
+        assert_eq!(r.utf16_len_in(all), chars + 2);
+        assert_eq!(r.utf16_len_in(1..5), 2);
+        assert_eq!(r.utf16_len_in(5..8), 2);
+        assert_eq!(r.utf16_len_in(5..5), 0);
+        assert_eq!(Rope::new().utf16_len_in(0..0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "byte 2 is not a char boundary of rope (len 13)")]
+    fn test_utf16_len_in_not_char_boundary() {
+        Rope::from_string("a\u{1f600}b\u{e9}\u{1d11e}c".to_string()).utf16_len_in(2..6);
+    }
+
+    #[test]
+    fn test_adjust_offset() {
+        // Insert 3 bytes at 5.
+        let insert = Edit { start_byte: 5, removed_len: 0, inserted_len: 3 };
+        assert_eq!(Rope::adjust_offset(0, &insert), 0);
+        assert_eq!(Rope::adjust_offset(4, &insert), 4);
+        assert_eq!(Rope::adjust_offset(5, &insert), 5);
+        assert_eq!(Rope::adjust_offset(6, &insert), 9);
+        assert_eq!(Rope::adjust_offset(20, &insert), 23);
+
+        // Remove 4 bytes at 5.
+        let remove = Edit { start_byte: 5, removed_len: 4, inserted_len: 0 };
+        assert_eq!(Rope::adjust_offset(4, &remove), 4);
+        assert_eq!(Rope::adjust_offset(5, &remove), 5);
+        assert_eq!(Rope::adjust_offset(7, &remove), 5);
+        assert_eq!(Rope::adjust_offset(9, &remove), 5);
+        assert_eq!(Rope::adjust_offset(20, &remove), 16);
+
+        // Replace 4 bytes at 5 with 2, checked against a real edit.
+        let mut r = Rope::from_string("0123456789abcdef".to_string());
+        let replace = Edit { start_byte: 5, removed_len: 4, inserted_len: 2 };
+        let marks: Vec<usize> = [1, 5, 6, 9, 12].iter().map(|&m| Rope::adjust_offset(m, &replace)).collect();
+        r.splice(5..9, "xy");
+        assert_eq!(marks, vec![1, 5, 5, 7, 10]);
+        assert_eq!(r.slice(marks[4]..marks[4] + 1).to_string(), "c");
+    }
+
+    #[test]
+    fn test_max_line_len() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("ab\nlongest line\n\nmid\u{e9}\n");
+        assert_eq!(r.max_line_len(), 12);
+
+        // The last line has no newline.
+        r.push_copy("the last line is longer");
+        assert_eq!(r.max_line_len(), 23);
+        assert_eq!(r.max_line_len(), r.lines().map(|l| l.to_string().len()).max().unwrap());
+
+        assert_eq!(Rope::from_string("\n\n".to_string()).max_line_len(), 0);
+        assert_eq!(Rope::new().max_line_len(), 0);
+    }
+
+    #[test]
+    fn test_chars_with_leaf() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("abcdefg");
+        r.push_copy("\u{e9}h");
+        assert_eq!(r.segments_in(0..r.len()).map(|(s, _)| s).collect::<Vec<_>>(),
+                   vec!["abc", "def", "g", "\u{e9}h"]);
+
+        let result: Vec<_> = r.chars_with_leaf().collect();
+        assert_eq!(result, vec![('a', 0, 0), ('b', 1, 0), ('c', 2, 0),
+                                ('d', 3, 1), ('e', 4, 1), ('f', 5, 1),
+                                ('g', 6, 2),
+                                ('\u{e9}', 7, 3), ('h', 9, 3)]);
+
+        // A char split between leaves belongs to the leaf it starts in.
+        let mut r: Rope = "a\u{20ac}\u{20ac}b".parse().unwrap();
+        r.remove(2, 5);
+        let result: Vec<_> = r.chars_with_leaf().collect();
+        assert_eq!(result, vec![('a', 0, 0), ('\u{20ac}', 1, 0), ('b', 4, 1)]);
+
+        assert_eq!(Rope::new().chars_with_leaf().count(), 0);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut r = Rope::from_string("Hello".to_string());
+        r.push_copy(" world!");
+        r.append_bytes(&[0xe2, 0x82]).unwrap();
+        r.clear();
+        assert_eq!(r.to_string(), "");
+        assert_eq!(r.len(), 0);
+        assert_eq!(r.capacity(), 0);
+
+        // The incomplete char was dropped too.
+        r.append_bytes(b"ok").unwrap();
+        assert_eq!(r.to_string(), "ok");
+    }
+
+    #[test]
+    fn test_clear_and_keep_capacity() {
+        let mut r = Rope::new();
+        r.push("a".repeat(100));
+        r.push_copy("small");
+        r.clear_and_keep_capacity();
+        assert_eq!(r.to_string(), "");
+        assert_eq!(r.len_lines(), 1);
+        let capacity = r.capacity();
+        assert!(capacity >= 100);
+        let buf = r.storage[0].as_ptr();
+
+        // Refilling uses the kept buffer while it has room.
+        for _ in 0..10 {
+            r.push_copy("0123456789");
         }
+        assert_eq!(r.to_string(), "0123456789".repeat(10));
+        assert_eq!(r.storage.len(), 1);
+        assert_eq!(r.storage[0].as_ptr(), buf);
+        assert_eq!(r.capacity(), capacity);
+        r.assert_pointers_valid();
+
+        r.push_copy(&"overflow".repeat(capacity));
+        assert_eq!(r.storage.len(), 2);
+        assert!(r.to_string().ends_with("overflow"));
+
+        r.clear();
+        assert_eq!(r.capacity(), 0);
+        r.clear_and_keep_capacity();
+        assert_eq!(r.capacity(), 0);
+        r.push_copy("x");
+        assert_eq!(r.to_string(), "x");
     }
-}
 
-impl Lnode {
-    fn remove(&mut self, start: usize, end: usize) -> NodeAction {
-        debug!("Lnode::remove: {}, {}, {}", start, end, self.len);
-        assert!(start <= self.len);
+    #[test]
+    fn test_lines_matching() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("fn main() {\n    let x = 1;\n}\n\nfn helper() -> usize {\n    let y = 2;\n    y\n}");
 
-        if start == 0 && end >= self.len {
-            // The removal span includes us, remove ourselves.
-            return NodeAction::Remove;
-        }
+        let found: Vec<_> = r.lines_matching("fn ").map(|(i, l)| (i, l.to_string())).collect();
+        assert_eq!(found, vec![(0, "fn main() {".to_string()),
+                               (4, "fn helper() -> usize {".to_string())]);
 
-        let old_len = self.len;
-        if start == 0 {
-            // Truncate the left of the node.
-            self.text = (self.text as usize + end) as *const u8;
-            self.len = old_len - end;
-            let delta = self.len as isize - old_len as isize;
-            return NodeAction::Adjust(delta);
-        }
+        let found: Vec<usize> = r.lines_matching("let").map(|(i, _)| i).collect();
+        assert_eq!(found, vec![1, 5]);
 
-        if end >= self.len {
-            // Truncate the right of the node.
-            self.len = start;
-            return NodeAction::Adjust(self.len as isize - old_len as isize);
-        }
+        // Matches do not span lines.
+        assert_eq!(r.lines_matching("{\n").count(), 0);
+        assert_eq!(r.lines_matching("missing").count(), 0);
 
-        let delta = -((end - start) as isize);
-        // Split the node (span to remove is in the middle of the node).
-        let new_node = Node::new_inner(
-            Some(Box::new(Node::new_leaf(self.text, start))),
-            Some(Box::new(Node::new_leaf((self.text as usize + end) as *const u8,
-                                    old_len - end))),
-            start);
-        return NodeAction::Change(Box::new(new_node), delta);
+        // Every line contains the empty string.
+        assert_eq!(r.lines_matching("").count(), r.len_lines());
     }
 
-    fn insert(&mut self, node: Box<Node>, start: usize) -> NodeAction {
-        let len = node.len();
-        if start == 0 {
-            // Insert at the start of the node
-            let new_node = Box::new(Node::new_inner(Some(node),
-                                                    Some(Box::new(Node::LeafNode(self.clone()))),
-                                                    len));
-            return NodeAction::Change(new_node, len as isize)
+    #[test]
+    fn test_utf16_range() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("a\u{1f600}b\u{e9}\u{1d11e}c\u{1f601}");
+
+        // One astral char before the start, two before the end.
+        assert_eq!(r.utf16_range(5..12), 3..7);
+        assert_eq!(r.utf16_range(1..5), 1..3);
+        assert_eq!(r.utf16_range(0..r.len()), 0..10);
+        assert_eq!(r.utf16_range(r.len()..r.len()), 10..10);
+        assert_eq!(r.utf16_range(5..5), 3..3);
+        for &(start, end) in &[(0, 5), (5, 8), (8, 13), (1, 17)] {
+            let range = r.utf16_range(start..end);
+            assert_eq!(range.end - range.start, r.utf16_len_in(start..end));
         }
+        assert_eq!(Rope::new().utf16_range(0..0), 0..0);
+    }
 
-        if start == self.len {
-            // Insert at the end of the node
-            let new_node = Box::new(Node::new_inner(Some(Box::new(Node::LeafNode(self.clone()))),
-                                                    Some(node),
-                                                    self.len));
-            return NodeAction::Change(new_node, len as isize)
+    #[test]
+    fn test_segment_count() {
+        let mut r = Rope::new_with_segment_size(8);
+        for i in 0..20 {
+            r.push_copy(&format!("{}", i % 10));
         }
+        r.push_copy("a longer leaf");
+        assert_eq!(r.segment_count(), 22);
+        assert_eq!(r.segment_count(), count_leaves(&r));
+        assert_eq!(r.average_segment_len(), 33.0 / 22.0);
+        let before = depth(&r);
 
-        // Insert into the middle of the node
-        let left = Some(Box::new(Node::new_leaf(self.text, start)));
-        let new_left = Box::new(Node::new_inner(left, Some(node), start));
-        let right = Some(Box::new(Node::new_leaf((self.text as usize + (start)) as *const u8,
-                                                  self.len - start)));
-        let new_node = Box::new(Node::new_inner(Some(new_left), right, start + len));
+        r.coalesce();
+        assert_eq!(r.to_string(), "01234567890123456789a longer leaf");
+        assert_eq!(r.segment_count(), 5);
+        assert_eq!(r.average_segment_len(), 33.0 / 5.0);
+        assert!(depth(&r) < before);
+        r.assert_pointers_valid();
+        assert_eq!(check_char_counts(&r.root), 33);
 
-        return NodeAction::Change(new_node, len as isize)
+        // Nothing more to merge.
+        r.coalesce();
+        assert_eq!(r.segment_count(), 5);
+
+        assert_eq!(Rope::new().segment_count(), 0);
+        assert_eq!(Rope::new().average_segment_len(), 0.0);
+        let mut r = Rope::new();
+        r.coalesce();
+        assert_eq!(r.to_string(), "");
     }
 
-    fn find_slice<'a>(&'a self, start: usize, end: usize, slice: &mut RopeSlice<'a>) {
-        debug!("Lnode::find_slice: {}, {}, {}", start, end, self.len);
-        debug_assert!(start < self.len, "Shouldn't have called this fn, we're out of bounds");
+    #[test]
+    fn test_replace_str_across_leaves() {
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("abcdefghij");
+        r.push_copy("klmn");
+        let leaves: Vec<_> = r.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
+        let capacity = r.capacity();
+        let storage = r.storage.len();
 
-        slice.nodes.push(self);
-        let mut len = ::std::cmp::min(end, self.len);
-        if start > 0 {
-            slice.start = start;
-            len -= start;
-        }
-        slice.len = len;
+        // Multi-byte chars which straddle leaf boundaries (so the rope can't
+        // be displayed leaf by leaf).
+        r.replace_str(2, "\u{e9}\u{2603}\u{1f600}");
+        assert_eq!(r.to_bytes(), "ab\u{e9}\u{2603}\u{1f600}lmn".as_bytes());
+        r.replace_str(11, "x\u{e9}");
+        assert_eq!(r.to_bytes(), "ab\u{e9}\u{2603}\u{1f600}x\u{e9}".as_bytes());
+        assert_eq!(r.len_chars(), 7);
+        assert_eq!(check_char_counts(&r.root), 7);
+
+        // No new storage, and the leaves were overwritten in place.
+        assert_eq!(r.capacity(), capacity);
+        assert_eq!(r.storage.len(), storage);
+        let after: Vec<_> = r.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
+        assert_eq!(after, leaves);
     }
 
-    fn replace(&mut self, start: usize, new_str: &str) {
-        println!("Lnode::replace: {}, {}, {}", start, new_str, self.len);
-        debug_assert!(start + new_str.bytes().len() <= self.len);
+    #[test]
+    fn test_count_chars() {
+        let text = "Hello, w\u{f6}rld! \u{3053}\u{3093}\u{306b}\u{3061}\u{306f} 123 \u{1f600}\u{e9}";
+        let mut r = Rope::new_with_segment_size(5);
+        r.push_copy(text);
+
+        assert_eq!(r.count_chars(char::is_alphabetic), text.chars().filter(|c| c.is_alphabetic()).count());
+        assert_eq!(r.count_chars(char::is_alphabetic), 16);
+        assert_eq!(r.count_chars(|c| c.is_ascii_digit()), 3);
+        assert_eq!(r.count_chars(|c| c.len_utf8() > 1), 8);
+        assert_eq!(r.count_chars(|_| true), r.len_chars());
+        assert_eq!(Rope::new().count_chars(|_| true), 0);
+    }
 
-        let addr = (self.text as usize + start) as *mut u8;
-        unsafe {
-            ::std::ptr::copy_nonoverlapping(new_str.as_ptr(), addr, new_str.bytes().len());
+    #[test]
+    fn test_count_chars_split_chars() {
+        let r = split_char_rope();
+        assert_eq!(r.count_chars(|_| true), 3);
+        assert_eq!(r.count_chars(|c| c == '\u{20ac}'), 1);
+
+        let text = "a\u{e9}\u{20ac}\u{1f600}b\u{3053} \u{f6}";
+        for size in 1..6 {
+            let r = rope_with_leaves_of(text, size);
+            assert_eq!(r.count_chars(|_| true), text.chars().count(), "leaf size {}", size);
+            assert_eq!(r.count_chars(|c| c.len_utf8() > 1), 5, "leaf size {}", size);
         }
     }
-}
 
+    #[test]
+    fn test_take_and_adopt_storage() {
+        let mut a = Rope::new_with_segment_size(4);
+        a.push("Hello, ".to_string());
+        a.push("w\u{f6}rld".to_string());
+        a.push("!\n".to_string());
+        let text = a.to_string();
+        let leaves: Vec<_> = a.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        let storage = a.take_storage();
+        assert_eq!(a.to_string(), "");
+        assert_eq!(a.len(), 0);
+        assert_eq!(a.capacity(), 0);
+        assert_eq!(storage.len(), 3);
+
+        // The new rope reads the same buffers; nothing was copied.
+        let mut b = Rope::new_with_segment_size(4);
+        b.adopt_storage(storage).unwrap();
+        assert_eq!(b.to_string(), text);
+        assert_eq!(b.len_chars(), text.chars().count());
+        let adopted: Vec<_> = b.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
+        assert_eq!(adopted, leaves);
+        b.assert_pointers_valid();
+
+        // Both ropes can still be edited.
+        b.insert_copy(0, ">> ");
+        b.remove(3, 5);
+        assert_eq!(b.to_string(), ">> llo, w\u{f6}rld!\n");
+        a.push_copy("reused");
+        assert_eq!(a.to_string(), "reused");
+
+        // Adopting appends to existing text.
+        let mut c = Rope::from_string("x".to_string());
+        c.adopt_storage(vec![b"yz".to_vec(), vec![], "\u{e9}".as_bytes().to_vec()]).unwrap();
+        assert_eq!(c.to_string(), "xyz\u{e9}");
+        c.assert_pointers_valid();
+
+        // Invalid buffers are rejected without changing the rope.
+        assert!(c.adopt_storage(vec![b"ok".to_vec(), vec![0xff]]).is_err());
+        assert_eq!(c.to_string(), "xyz\u{e9}");
+
+        // An edited rope's storage is compacted to its text first.
+        let mut d: Rope = "ac".parse().unwrap();
+        d.insert_copy(1, "b");
+        let storage = d.take_storage();
+        assert_eq!(storage, [b"abc".to_vec()]);
+        let mut e = Rope::new();
+        e.adopt_storage(storage).unwrap();
+        assert!(e.to_string() == "abc");
+
+        let mut d: Rope = "tail".parse().unwrap();
+        d.prepend("pre");
+        d.remove(0, 1);
+        let mut e = Rope::new();
+        e.adopt_storage(d.take_storage()).unwrap();
+        assert!(e.to_string() == "retail");
+        e.assert_pointers_valid();
+        assert_eq!(c.storage.len(), 3);
+    }
 
     #[test]
-    fn test_new() {
-        let r = Rope::new();
-        assert!(r.len() == 0);
-        assert!(r.to_string() == "");
+    fn test_line_index() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("first\nsecond line\n\n\u{e9}t\u{e9}\nlast");
+        let index = r.build_line_index();
 
-        let r = Rope::from_string("Hello world!".to_string());
-        assert!(r.len() == 12);
-        assert!(r.to_string() == "Hello world!");
+        assert_eq!(index.len_lines(), r.len_lines());
+        for byte in 0..r.len() + 1 {
+            assert_eq!(index.line_at(byte), r.byte_to_line(byte), "byte {}", byte);
+        }
+        for line in 0..r.len_lines() + 1 {
+            assert_eq!(index.offset_of_line(line), r.line_to_byte(line), "line {}", line);
+        }
+
+        // A trailing newline starts an empty last line.
+        r.push_copy("\n");
+        let index = r.build_line_index();
+        assert_eq!(index.len_lines(), 6);
+        assert_eq!(index.line_at(r.len()), 5);
+        assert_eq!(index.offset_of_line(5), r.len());
+
+        let index = Rope::new().build_line_index();
+        assert_eq!(index.len_lines(), 1);
+        assert_eq!(index.line_at(0), 0);
+        assert_eq!(index.offset_of_line(1), 0);
     }
 
     #[test]
-    fn test_from_string() {
-        let r: Rope = "Hello world!".parse().unwrap();
-        assert!(r.to_string() == "Hello world!");
+    #[should_panic(expected = "line 3 out of bounds of rope (2 lines)")]
+    fn test_line_index_out_of_bounds() {
+        Rope::from_string("a\nb".to_string()).build_line_index().offset_of_line(3);
     }
 
     #[test]
-    fn test_slice_empty_rope() {
-        let r: Rope = Rope::new();
-        let _ = r.full_slice();
+    fn test_swap() {
+        // Move the second line down.
+        let mut r = Rope::new_with_segment_size(4);
+        r.push_copy("one\ntwo\nthree\nfour");
+        let two = r.line_byte_range(1).unwrap();
+        let three = r.line_byte_range(2).unwrap();
+        assert_eq!(r.swap(two, three), Ok(()));
+        assert_eq!(r.to_string(), "one\nthree\ntwo\nfour");
+
+        // Non-adjacent ranges of different lengths, in either order.
+        let mut r = Rope::from_string("abc-\u{e9}\u{e9}-defgh".to_string());
+        assert_eq!(r.swap(9..14, 0..1), Ok(()));
+        assert_eq!(r.to_string(), "defghbc-\u{e9}\u{e9}-a");
+        assert_eq!(r.swap(8..12, 0..5), Ok(()));
+        assert_eq!(r.to_string(), "\u{e9}\u{e9}bc-defgh-a");
+
+        // An empty range moves the other range's text.
+        assert_eq!(r.swap(0..4, 12..12), Ok(()));
+        assert_eq!(r.to_string(), "bc-defgh\u{e9}\u{e9}-a");
     }
 
     #[test]
-    fn test_remove() {
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.remove(0, 10);
-        assert!(r.to_string() == "d!");
+    fn test_swap_errors() {
+        let mut r = Rope::from_string("ab\u{e9}cdef".to_string());
+        assert_eq!(r.swap(0..2, 1..4), Err(RopeError::Overlap(0..2, 1..4)));
+        assert_eq!(r.swap(5..6, 0..7), Err(RopeError::Overlap(5..6, 0..7)));
+        assert_eq!(r.swap(0..1, 3..5), Err(RopeError::NotCharBoundary(3)));
+        assert_eq!(r.swap(0..1, 5..9), Err(RopeError::OutOfBounds { offset: 9, len: 8 }));
+        assert_eq!(r.to_string(), "ab\u{e9}cdef");
+        assert_eq!(RopeError::Overlap(0..2, 1..4).to_string(), "ranges 0..2 and 1..4 overlap");
 
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.remove(4, 12);
-        assert!(r.to_string() == "Hell");
+        assert_eq!(r.swap(0..1, 4..2), Err(RopeError::ReversedRange(4..2)));
+        assert_eq!(r.swap(4..2, 0..1), Err(RopeError::ReversedRange(4..2)));
+        assert_eq!(r.to_string(), "ab\u{e9}cdef");
+        assert_eq!(RopeError::ReversedRange(4..2).to_string(), "range 4..2 is reversed");
+    }
 
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.remove(4, 10);
-        assert!(r.to_string() == "Helld!");
+    #[test]
+    fn test_from_str_stripping_bom() {
+        let r = Rope::from_str_stripping_bom("\u{feff}h\u{e9}llo\nworld");
+        assert_eq!(r.to_string(), "h\u{e9}llo\nworld");
+        assert_eq!(r.len(), 12);
+        assert_eq!(r.chars().next(), Some(('h', 0)));
+        assert_eq!(r.find("world"), Some(7));
+        assert_eq!(r.line_to_byte(1), 7);
+
+        let r = Rope::from_str_stripping_bom("h\u{e9}llo");
+        assert_eq!(r.to_string(), "h\u{e9}llo");
+
+        // Only a single, leading BOM is removed.
+        let r = Rope::from_str_stripping_bom("\u{feff}\u{feff}a\u{feff}");
+        assert_eq!(r.to_string(), "\u{feff}a\u{feff}");
+        assert_eq!(Rope::from_str_stripping_bom("\u{feff}").len(), 0);
     }
 
     #[test]
-    fn test_insert_copy() {
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.insert_copy(0, "foo");
-        assert!(r.to_string() == "fooHello world!");
-        assert!(r.slice(2..8).to_string() == "oHello");
+    fn test_find_from() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("one fish, two fish, r\u{e9}d fish, blue fish");
 
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.insert_copy(12, "foo");
-        assert!(r.to_string() == "Hello world!foo");
-        assert!(r.slice(2..8).to_string() == "llo wo");
+        let mut found = vec![];
+        let mut start = 0;
+        while let Some(i) = r.find_from("fish", start) {
+            found.push(i);
+            start = i + "fish".len();
+        }
+        assert_eq!(found, vec![4, 14, 25, 36]);
+        // The matches span leaf boundaries.
+        assert!(found.iter().any(|&i| r.slice(i..i + 4).nodes.len() > 1));
 
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.insert_copy(5, "foo");
-        assert!(r.to_string() == "Hellofoo world!");
-        assert!(r.slice(2..8).to_string() == "llofoo");
+        assert_eq!(r.find_from("one", 1), None);
+        assert_eq!(r.find_from("fish", 5), Some(14));
+        assert_eq!(r.find_from("fish", r.len()), None);
+        assert_eq!(r.find_from("", 7), Some(7));
+        assert_eq!(r.find_from("\u{e9}d", 10), Some(21));
     }
 
     #[test]
-    fn test_push_copy() {
-        let mut r: Rope = "Hello world!".parse().unwrap();
-        r.push_copy("foo");
-        assert!(r.to_string() == "Hello world!foo");
-        assert!(r.slice(2..8).to_string() == "llo wo");
+    #[should_panic(expected = "byte 5 out of bounds of rope (len 4)")]
+    fn test_find_from_out_of_bounds() {
+        Rope::from_string("fish".to_string()).find_from("f", 5);
     }
 
     #[test]
-    fn test_insert_replace() {
-        let mut r: Rope = "hello worl\u{00bb0}!".parse().unwrap();
-        r.insert_copy(5, "bb");
-        assert!(r.to_string() == "hellobb worlர!");
-        r.replace(0, 'H');
-        r.replace(15, '~');
-        r.replace_str(5, "fo\u{00cb0}");
-        assert!(r.to_string() == "Hellofoರrlர~");
-        assert!(r.slice(0..10).to_string() == "Hellofoರ");
-        assert!(r.slice(5..10).to_string() == "foರ");
-        assert!(r.slice(10..15).to_string() == "rlர");
+    fn test_rfind_from() {
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy("one fish, two fish, r\u{e9}d fish, blue fish");
 
-        let expected = "Hellofoರrlர~";
-        let mut byte_pos = 0;
-        for ((c, b), e) in r.chars().zip(expected.chars()) {
-            assert!(c == e);
-            assert!(b == byte_pos);
-            byte_pos += e.len_utf8();
+        let mut found = vec![];
+        let mut end = r.len();
+        while let Some(i) = r.rfind_from("fish", end) {
+            found.push(i);
+            end = i;
         }
+        assert_eq!(found, vec![36, 25, 14, 4]);
+
+        // The match must end by `end`.
+        assert_eq!(r.rfind_from("fish", 28), Some(14));
+        assert_eq!(r.rfind_from("fish", 29), Some(25));
+        assert_eq!(r.rfind_from("one", 2), None);
+        assert_eq!(r.rfind_from("one", 3), Some(0));
+        assert_eq!(r.rfind_from("\u{e9}d", r.len()), Some(21));
+        assert_eq!(r.rfind_from("", 7), Some(7));
+        assert_eq!(Rope::new().rfind_from("a", 0), None);
     }
 
     #[test]
-    fn test_slice_iter() {
-        let mut r: Rope = "Helloworld!".parse().unwrap();
-        r.insert_copy(5, " ");
+    fn test_truncate_lines() {
+        let text = "one\ntwo\nthr\u{e9}e\nfour\n";
+        let mut r = Rope::new_with_segment_size(3);
+        r.push_copy(text);
+        assert_eq!(r.len_lines(), 5);
 
-        let mut slice = r.slice(3..8).iter_chars();
+        // More lines than there are, or exactly as many, is a no-op.
+        r.truncate_lines(10);
+        assert_eq!(r.to_string(), text);
+        r.truncate_lines(5);
+        assert_eq!(r.to_string(), text);
 
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(Some(('o', 4)), slice.next());
-        assert_eq!(Some((' ', 5)), slice.next());
-        assert_eq!(Some(('w', 6)), slice.next());
-        assert_eq!(Some(('o', 7)), slice.next());
-        assert_eq!(Some(('r', 8)), slice.next());
-        assert_eq!(None, slice.next());
+        // Dropping the empty last line drops the trailing newline.
+        r.truncate_lines(4);
+        assert_eq!(r.to_string(), "one\ntwo\nthr\u{e9}e\nfour");
+        r.truncate_lines(2);
+        assert_eq!(r.to_string(), "one\ntwo");
+        assert_eq!(r.len_lines(), 2);
+        r.truncate_lines(1);
+        assert_eq!(r.to_string(), "one");
+        r.truncate_lines(0);
+        assert_eq!(r.to_string(), "");
+
+        let mut r = Rope::from_string("\n\n\n".to_string());
+        r.truncate_lines(2);
+        assert_eq!(r.to_string(), "\n");
     }
 
     #[test]
-    fn test_slice_from_start() {
-        let mut r: Rope = "Helloworld!".parse().unwrap();
-        r.insert_copy(5, " ");
+    fn test_tail() {
+        let mut r = Rope::new_with_segment_size(4);
+        for i in 1..11 {
+            r.push_copy(&format!("line {}\n", i));
+        }
+        assert_eq!(r.tail(3).to_string(), "line 8\nline 9\nline 10\n");
+        assert_eq!(r.tail(1).to_string(), "line 10\n");
+        assert_eq!(r.tail(10).to_string(), r.to_string());
+        assert_eq!(r.tail(50).to_string(), r.to_string());
+        assert_eq!(r.tail(0).to_string(), "");
 
-        let mut slice = r.slice(0..6).iter_chars();
+        // Without a trailing newline.
+        r.truncate(r.len() - 1);
+        assert_eq!(r.tail(3).to_string(), "line 8\nline 9\nline 10");
 
-        assert_eq!(Some(('H', 0)), slice.next());
-        assert_eq!(Some(('e', 1)), slice.next());
-        assert_eq!(Some(('l', 2)), slice.next());
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(Some(('o', 4)), slice.next());
-        assert_eq!(Some((' ', 5)), slice.next());
-        assert_eq!(None, slice.next());
+        let r = Rope::from_string("\n\n".to_string());
+        assert_eq!(r.tail(1).to_string(), "\n");
+        assert_eq!(r.tail(2).to_string(), "\n\n");
+        assert_eq!(Rope::new().tail(3).to_string(), "");
     }
 
     #[test]
-    fn test_slice_iter_no_split() {
-        let r: Rope = "Hello world!".parse().unwrap();
+    fn test_balance() {
+        let mut r = Rope::new();
+        let mut expected = String::new();
+        for i in 0..1000 {
+            let c = (b'a' + (i % 26) as u8) as char;
+            r.push_copy(&c.to_string());
+            expected.push(c);
+        }
+        assert!(depth(&r) >= 1000);
+        let leaves: Vec<_> = r.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
+        let capacity = r.capacity();
 
-        let mut slice = r.slice(3..8).iter_chars();
+        r.balance();
+        assert_eq!(r.to_string(), expected);
+        // 1000 leaves need a depth of at least log2(1000) + 1, about 11.
+        assert_eq!(count_leaves(&r), 1000);
+        assert!(depth(&r) <= 11, "depth {}", depth(&r));
+        check_char_counts(&r.root);
 
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(Some(('o', 4)), slice.next());
-        assert_eq!(Some((' ', 5)), slice.next());
-        assert_eq!(Some(('w', 6)), slice.next());
-        assert_eq!(Some(('o', 7)), slice.next());
-        assert_eq!(Some(('r', 8)), slice.next());
-        assert_eq!(None, slice.next());
+        // The leaves still point at the same text.
+        let after: Vec<_> = r.full_slice().nodes.iter().map(|l| (l.text, l.len)).collect();
+        assert_eq!(after, leaves);
+        assert_eq!(r.capacity(), capacity);
+        r.assert_pointers_valid();
+
+        // The balanced rope can still be edited.
+        r.insert_copy(500, "\u{e9}");
+        r.remove(0, 26);
+        expected.insert(500, '\u{e9}');
+        expected.replace_range(0..26, "");
+        assert_eq!(r.to_string(), expected);
+
+        let mut r = Rope::new();
+        r.balance();
+        assert_eq!(r.to_string(), "");
     }
 
     #[test]
-    fn test_slice_iter_special_chars() {
-        let r: Rope = "Hello©world".parse().unwrap();
-        let mut slice = r.slice(3..8).iter_chars();
+    fn test_normalize_newlines_many() {
+        let mut r = Rope::new_with_segment_size(64);
+        for _ in 0..1000 {
+            r.push_copy(&"ab\r\n".repeat(100));
+        }
+        assert_eq!(r.len_lines(), 100_001);
 
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(Some(('o', 4)), slice.next());
-        assert_eq!(Some(('\u{a9}', 5)), slice.next());
-        assert_eq!(Some(('w', 7)), slice.next());
-        assert_eq!(Some(('o', 8)), slice.next());
-        assert_eq!(None, slice.next());
+        r.normalize_newlines(LineEnding::Lf);
+        assert_eq!(r.len(), 300_000);
+        assert_eq!(r.len_lines(), 100_001);
+        assert_eq!(r.to_string(), "ab\n".repeat(100_000));
+        // The result is one balanced tree, not a chain of splices.
+        assert!(depth(&r) <= 20, "depth {}", depth(&r));
+
+        r.normalize_newlines(LineEnding::CrLf);
+        assert_eq!(r.to_string(), "ab\r\n".repeat(100_000));
     }
 
     #[test]
-    fn test_slice_iter_special_chars_split() {
-        let mut r: Rope = "Helloworld".parse().unwrap();
-        r.insert_copy(5, "©");
+    fn test_map_lines_many() {
+        let mut r = Rope::new_with_segment_size(64);
+        for _ in 0..10_000 {
+            r.push_copy("0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n");
+        }
 
-        let mut slice = r.slice(3..8).iter_chars();
+        r.map_lines(|i, line| if i % 2 == 0 { Some(format!("<{}>", line)) } else { None });
+        assert_eq!(r.len_lines(), 100_001);
+        assert_eq!(r.lines().nth(4).unwrap().to_string(), "<4>");
+        assert_eq!(r.lines().nth(5).unwrap().to_string(), "5");
+        // The trailing empty line is mapped too.
+        assert!(r.to_string().ends_with("9\n<>"));
+        assert!(depth(&r) <= 20, "depth {}", depth(&r));
+    }
 
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(Some(('o', 4)), slice.next());
-        assert_eq!(Some(('\u{a9}', 5)), slice.next());
-        assert_eq!(Some(('w', 7)), slice.next());
-        assert_eq!(Some(('o', 8)), slice.next());
-        assert_eq!(None, slice.next());
+    #[test]
+    fn test_dedup_adjacent_lines_many() {
+        let mut r = Rope::new_with_segment_size(64);
+        for _ in 0..10_000 {
+            r.push_copy("a\na\nb\nb\nb\nc\nc\nc\nc\nd\n");
+        }
+
+        r.dedup_adjacent_lines();
+        assert_eq!(r.len_lines(), 40_001);
+        assert_eq!(r.to_string(), "a\nb\nc\nd\n".repeat(10_000));
+        assert!(depth(&r) <= 20, "depth {}", depth(&r));
     }
 
     #[test]
-    fn test_small_slice_single_node() {
-        let r: Rope = "Hello world".parse().unwrap();
-        let mut slice = r.slice(1..3).iter_chars();
+    fn test_retain_lines_many() {
+        let mut r = Rope::new_with_segment_size(64);
+        for _ in 0..10_000 {
+            r.push_copy("0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n");
+        }
 
-        assert_eq!(Some(('e', 1)), slice.next());
-        assert_eq!(Some(('l', 2)), slice.next());
-        assert_eq!(Some(('l', 3)), slice.next());
-        assert_eq!(None, slice.next());
+        r.retain_lines(|i, _| i % 10 == 3);
+        assert_eq!(r.len_lines(), 10_001);
+        assert_eq!(r.to_string(), "3\n".repeat(10_000));
+        assert!(depth(&r) <= 20, "depth {}", depth(&r));
     }
 
     #[test]
-    fn test_small_slice_start_single_node() {
-        let r: Rope = "Hello world".parse().unwrap();
-        let mut slice = r.slice(0..1).iter_chars();
+    fn test_prepend_after_rebuild() {
+        let mut r = Rope::new_with_segment_size(16);
+        let mut s = String::new();
+        for i in 0..200 {
+            let text = format!("{} ", i % 10);
+            r.prepend(&text);
+            s.insert_str(0, &text);
+        }
 
-        assert_eq!(Some(('H', 0)), slice.next());
-        assert_eq!(Some(('e', 1)), slice.next());
-        assert_eq!(None, slice.next());
+        r.balance();
+        r.prepend("ab");
+        s.insert_str(0, "ab");
+        assert!(r.to_string() == s);
+        r.assert_pointers_valid();
+
+        r.coalesce();
+        r.prepend("cd");
+        s.insert_str(0, "cd");
+        assert!(r.to_string() == s);
+        r.assert_pointers_valid();
+    }
+
+    #[test]
+    fn test_prepend_leaf_at_buffer_end() {
+        let mut r = Rope::new();
+        r.prepend("x");
+        let i = r.front.unwrap();
+        // Make the leftmost leaf start exactly at the end of the front buffer,
+        // as a leaf in a buffer allocated straight after it would.
+        let offset = r.storage[i].len() - 1;
+        r.storage[i].truncate(offset);
+
+        r.prepend("y");
+        assert!(r.to_bytes() == b"yx");
+        assert_eq!(r.storage.len(), 2);
+    }
+
+    // Helper methods.
+    fn count_leaves(r: &Rope) -> usize {
+        r.full_slice().nodes.len()
+    }
+    fn depth(r: &Rope) -> usize {
+        node_depth(&r.root)
+    }
+    fn node_count(n: &Node) -> usize {
+        match *n {
+            Node::InnerNode(Inode { ref left, ref right, .. }) => {
+                let left = left.as_ref().map(|n| node_count(n)).unwrap_or(0);
+                let right = right.as_ref().map(|n| node_count(n)).unwrap_or(0);
+                1 + left + right
+            }
+            Node::LeafNode(_) => 1,
+        }
+    }
+    // Check the cached char counts in `n` against a recount of its leaves,
+    // returning the number of chars in `n`.
+    fn check_char_counts(n: &Node) -> usize {
+        match *n {
+            Node::InnerNode(Inode { ref left, ref right, char_weight, .. }) => {
+                let left = left.as_ref().map(|n| check_char_counts(n)).unwrap_or(0);
+                let right = right.as_ref().map(|n| check_char_counts(n)).unwrap_or(0);
+                assert_eq!(char_weight, left);
+                left + right
+            }
+            Node::LeafNode(ref l) => {
+                assert_eq!(l.chars, char_count(l.bytes()));
+                l.chars
+            }
+        }
+    }
+    // "a\u{20ac}b", with the euro sign split between two leaves: removing
+    // bytes from the middle of two chars leaves one valid char behind.
+    fn split_char_rope() -> Rope {
+        let mut r: Rope = "a\u{20ac}\u{20ac}b".parse().unwrap();
+        r.remove(2, 5);
+        assert!(r.has_split_chars());
+        r
+    }
+    // A rope of `text` in leaves of `size` bytes, regardless of char
+    // boundaries.
+    fn rope_with_leaves_of(text: &str, size: usize) -> Rope {
+        let buf = text.as_bytes().to_vec();
+        let leaves: Vec<Lnode> = buf.chunks(size)
+                                    .map(|c| Lnode::new(c.as_ptr(), c.len()))
+                                    .collect();
+        let mut r = Rope::new();
+        if !leaves.is_empty() {
+            r.root = Node::from_leaves(&leaves);
+        }
+        r.storage.push(buf);
+        r.len = text.len();
+        r
+    }
+    fn node_depth(n: &Node) -> usize {
+        match *n {
+            Node::InnerNode(Inode { ref left, ref right, .. }) => {
+                let left = left.as_ref().map(|n| node_depth(n)).unwrap_or(0);
+                let right = right.as_ref().map(|n| node_depth(n)).unwrap_or(0);
+                1 + ::std::cmp::max(left, right)
+            }
+            Node::LeafNode(_) => 1,
+        }
     }
 }
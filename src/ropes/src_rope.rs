@@ -135,6 +135,10 @@ impl Rope {
         self.remove_inner(start, end, |this| this.root.src_remove(start, end, start))
     }
 
+    // Storage is never shared, so there is nothing to do before an in-place
+    // edit.
+    fn unshare(&mut self) {}
+
 
     // TODO src_replace
     // TODO src_replace_str
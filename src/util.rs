@@ -23,3 +23,35 @@ static UTF8_CHAR_WIDTH: [u8; 256] = [
 pub fn utf8_char_width(b: u8) -> usize {
     return UTF8_CHAR_WIDTH[b as usize] as usize;
 }
+
+/// Whether `b` is the first byte of a UTF-8 character (i.e., not a
+/// continuation byte).
+#[inline]
+pub fn is_char_start(b: u8) -> bool {
+    (b as i8) >= -0x40
+}
+
+/// The number of chars which start in `bytes`.
+#[inline]
+pub fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| is_char_start(b)).count()
+}
+
+/// Decode a char given its first byte and an iterator over the bytes which
+/// follow it.
+pub fn decode_char<I>(first: u8, rest: &mut I) -> char
+    where I: Iterator<Item = u8>
+{
+    let width = utf8_char_width(first);
+    if width == 0 {
+        panic!("non-utf8 char");
+    }
+    let mut buf = [first, 0, 0, 0];
+    for b in buf[1..width].iter_mut() {
+        *b = rest.next().expect("truncated utf8 char");
+    }
+    match ::std::str::from_utf8(&buf[..width]).ok() {
+        Some(s) => s.chars().next().expect("FATAL: we checked presence of this before"),
+        None => panic!("bad utf8 char"),
+    }
+}